@@ -1,4 +1,9 @@
+export type Operands = [number, number];
 
-export function add(a, b) {
+export enum Op {
+    Add,
+}
+
+export function add(a: number, b: number): number {
     return a + b;
 }
@@ -1,3 +1,5 @@
 import { add } from './foo';
-const x = (add(1, 2));
+import type { Operands } from './foo';
+const operands: Operands = [1, 2];
+const x = (add(...operands));
 console.log(x);
@@ -1,310 +1,5825 @@
-use std::sync::Arc;
-use std::{collections::HashMap, path::PathBuf};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::ExitCode;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex, RwLock,
+};
+use std::time::{Duration, Instant, SystemTime};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    path::{Path, PathBuf},
+};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Module {
     ast: Ast,
-    deps: HashMap<String, PathBuf>,
+    /// Dependencies paired with their resolved paths, externals excluded
+    deps: Vec<(Dependency, PathBuf)>,
+    /// Externals by specifier
+    externals: BTreeMap<String, ExternalModule>,
+    /// Source map referenced by a `sourceMappingURL` comment in the loaded file,
+    /// kept as JSON since `sourcemap::SourceMap` can't be sent across threads
+    input_source_map: Option<Vec<u8>>,
+    bindings: ModuleBindings,
+    side_effects: bool,
+    /// Modification time of the loaded file when it was read
+    mtime: Option<SystemTime>,
+    /// Stylesheet extracted into the CSS file of the bundle
+    css: Option<ExtractedCss>,
+    asset: Option<Asset>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+struct ExtractedCss {
+    /// `@import`s of external stylesheets, which have to come first
+    imports: String,
+    code: String,
+}
+
+/// A file copied to the output directory, loaded as a module exporting its URL.
+#[derive(Debug, Clone)]
+struct Asset {
+    filename: String,
+    content: Arc<[u8]>,
+}
+
+#[derive(Debug, Clone)]
+struct Dependency {
+    specifier: String,
+    kind: DependencyKind,
+    span: Span,
+    /// Line and column of the specifier in the loaded file, when `span` points
+    /// into code read back from the cache
+    origin: Option<(usize, usize)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum DependencyKind {
+    /// `import x from 'x'`
+    Static,
+    /// `export * from 'x'` and `export { x } from 'x'`
+    ReExport,
+    /// `import('x')`
+    Dynamic,
+    /// `require('x')`
+    Require,
+    /// `new Worker(new URL('x', import.meta.url))`
+    Worker,
+    /// `new URL('x', import.meta.url)`
+    Url,
+    /// `module.hot.accept('x')`, only rewritten to the module id
+    HotAccept,
+}
+
+#[derive(Debug, Clone)]
 struct Ast {
     ast: SwcModule,
     unresolved_mark: Mark,
     top_level_mark: Mark,
 }
 
+#[derive(Clone)]
 struct ModuleGraph {
-    modules: HashMap<String, Module>,
+    /// In discovery order, so the output does not depend on scheduling
+    modules: IndexMap<String, Module>,
 }
 
 struct Context {
     root: PathBuf,
+    config: Config,
+    entries: Vec<PathBuf>,
+    output: PathBuf,
+    /// Parsed `define` replacements
+    defines: HashMap<String, Expr>,
     cm: Lrc<SourceMap>,
     comments: SwcComments,
     globals: Globals,
+    resolvers: Resolvers,
+    /// Hot module replacement, only when serving
+    hmr: bool,
+    cache: Option<Cache>,
+}
+
+impl Context {
+    fn react_refresh(&self) -> bool {
+        self.hmr && self.config.dev_server.react_refresh
+    }
 }
 
 struct CompileParams {
     root: PathBuf,
+    config: Config,
+    hmr: bool,
 }
 
-fn compile(params: CompileParams) {
+fn compile(params: CompileParams) -> Result<(), CompileError> {
+    let context = create_context(params)?;
+    let mut module_graph = build(BuildParams {
+        entries: context.entries.clone(),
+        context: context.clone(),
+    })
+    .map_err(CompileError::Build)?;
+    let output = generate(&mut module_graph, context.clone()).map_err(CompileError::Generate)?;
+    write_output(&output.files, context).map_err(CompileError::Generate)
+}
+
+/// Resolves entries and parses defines.
+fn create_context(params: CompileParams) -> Result<Arc<Context>, CompileError> {
     let root = params.root;
-    let context = Arc::new(Context {
-        root: root.clone(),
-        cm: Default::default(),
+    let config = params.config;
+    let mut seen = HashSet::new();
+    let entries = config
+        .entry
+        .iter()
+        .map(|entry| {
+            let entry = root.join(entry);
+            // resolved dependencies are canonical, `./index.ts` must be the same module
+            entry.canonicalize().unwrap_or(entry)
+        })
+        .filter(|entry| seen.insert(entry.clone()))
+        .collect::<Vec<_>>();
+    let output = root.join(&config.output.path);
+    let cm: Lrc<SourceMap> = Default::default();
+    let mut defines = HashMap::new();
+    let mut errors = vec![];
+    config.define().into_iter().for_each(|(key, code)| {
+        let path = PathBuf::from(format!("define:{}", key));
+        match code_to_expr(code, &path, cm.clone()) {
+            Ok(expr) => {
+                defines.insert(key, expr);
+            }
+            Err(errs) => errors.push(syntax_error(&path, errs, cm.clone())),
+        }
+    });
+    if !errors.is_empty() {
+        return Err(CompileError::Build(errors));
+    }
+    let resolvers = Resolvers::new(&root, &config);
+    let cache = config
+        .cache
+        .then(|| Cache::new(&root, &config, &entries, params.hmr))
+        .flatten();
+    Ok(Arc::new(Context {
+        root,
+        config,
+        entries,
+        output,
+        defines,
+        cm,
         comments: Default::default(),
         globals: Default::default(),
+        resolvers,
+        hmr: params.hmr,
+        cache,
+    }))
+}
+
+/////////////////////////////////////////
+// Config
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields, default)]
+struct Config {
+    entry: Vec<PathBuf>,
+    output: OutputConfig,
+    resolve: ResolveConfig,
+    targets: Option<preset_env::Targets>,
+    /// Modules loaded at runtime instead of bundled, see `External`
+    #[serde(deserialize_with = "deserialize_externals")]
+    externals: Vec<External>,
+    platform: Platform,
+    /// Expression to replace, e.g. `process.env.API`, to the code replacing it
+    define: HashMap<String, serde_json::Value>,
+    mode: Mode,
+    /// Defaults to `true` in production mode
+    minify: Option<bool>,
+    minify_options: MinifyConfig,
+    /// Defaults to `true` in production mode
+    tree_shaking: Option<bool>,
+    /// Concatenates ES modules into a single scope, defaults to `true` in production mode
+    scope_hoisting: Option<bool>,
+    /// Reports what tree shaking removed
+    debug: bool,
+    /// Defaults to `"path"` in development mode and `"hash"` in production mode
+    module_ids: Option<ModuleIds>,
+    /// `true` or `"external"`, `"inline"`, `"hidden"`
+    #[serde(deserialize_with = "deserialize_sourcemap")]
+    sourcemap: Option<SourceMapMode>,
+    public_path: String,
+    /// Caches transformed modules in `node_modules/.cache/mako`, in projects
+    /// having a `node_modules` directory
+    cache: bool,
+    jsx: JsxConfig,
+    css: CssConfig,
+    assets: AssetsConfig,
+    dev_server: DevServerConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields, default)]
+struct OutputConfig {
+    path: PathBuf,
+    /// `[name]` is replaced by the chunk name
+    filename: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields, default)]
+struct ResolveConfig {
+    /// Specifier prefix to a root-relative path, or to another specifier
+    alias: BTreeMap<String, String>,
+    extensions: Vec<String>,
+    /// `exports` and `imports` conditions besides `import` and `require`,
+    /// defaults to the platform and the mode
+    conditions: Option<Vec<String>>,
+    /// `package.json` fields tried in order when there are no `exports`,
+    /// defaults to `browser`, `module` and `main`, without `browser` on Node
+    main_fields: Option<Vec<String>>,
+    /// For `compilerOptions.paths`, defaults to `tsconfig.json` in the root
+    /// when present
+    tsconfig: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields, default)]
+struct JsxConfig {
+    runtime: JsxRuntime,
+    /// Package providing `jsx-runtime` for the automatic runtime, defaults to `react`
+    import_source: Option<String>,
+    /// Classic runtime element factory, defaults to `React.createElement`
+    pragma: Option<String>,
+    /// Classic runtime fragment, defaults to `React.Fragment`
+    pragma_frag: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum JsxRuntime {
+    /// `jsx()` calls imported from `<importSource>/jsx-runtime`
+    #[default]
+    Automatic,
+    /// `React.createElement` calls, `React` has to be in scope
+    Classic,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields, default)]
+struct CssConfig {
+    /// Writes stylesheets to a `.css` file next to the entry chunk instead of
+    /// injecting `<style>` tags at runtime, defaults to `true` in production mode
+    extract: Option<bool>,
+    /// For `*.module.css` files
+    modules: CssModulesConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields, default)]
+struct CssModulesConfig {
+    /// `[name]` is replaced by the file name without `.module.css`, `[local]`
+    /// by the class name and `[hash]` by a hash of the file path and the class name
+    class_name: String,
+    /// Writes `<file>.module.css.d.ts` declaring the class names
+    dts: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields, default)]
+struct AssetsConfig {
+    /// `[name]`, `[hash]` and `[ext]` are replaced by the file name, a hash
+    /// of the content and the extension with its dot
+    filename: String,
+    /// Assets smaller than this many bytes are inlined as data URIs, unless
+    /// imported with `?url`
+    inline_limit: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields, default)]
+struct DevServerConfig {
+    port: u16,
+    /// Hot module replacement, falling back to reloading the page
+    hmr: bool,
+    /// Keeps React component state on hot updates, needs `react-refresh`
+    /// installed
+    react_refresh: bool,
+    /// Path prefix, e.g. `/api`, to the `http://` server requests are forwarded to
+    proxy: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields, default)]
+struct MinifyConfig {
+    keep_class_names: bool,
+    keep_fn_names: bool,
+    drop_console: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum Mode {
+    #[default]
+    Development,
+    Production,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Platform {
+    /// Node built-ins are replaced by their polyfills when installed, or by
+    /// empty modules
+    #[default]
+    Browser,
+    /// Node built-ins are `require`d
+    Node,
+}
+
+/// A module loaded at runtime, configured as `"specifier": "[type ]name"`.
+/// The specifier is an exact name, a prefix ending in `*` or a `/regex/`, and
+/// `[request]` in the name is replaced by the imported specifier.
+#[derive(Debug)]
+struct External {
+    pattern: ExternalPattern,
+    kind: ExternalKind,
+    name: String,
+}
+
+#[derive(Debug)]
+enum ExternalPattern {
+    Exact(String),
+    Prefix(String),
+    Regex(regex::Regex),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExternalKind {
+    /// `globalThis.name`, the default
+    Global,
+    /// `require(name)`
+    CommonJs,
+    /// `import * as x from name`, the entry chunk has to be loaded as a module
+    Module,
+    /// `{}`, for Node built-ins without a polyfill in the browser
+    Empty,
+}
+
+/// An external as imported by a module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ExternalModule {
+    kind: ExternalKind,
+    name: String,
+}
+
+impl External {
+    fn parse(specifier: &str, value: &str) -> Result<Self, String> {
+        let pattern = if let Some(regex) = specifier
+            .strip_prefix('/')
+            .and_then(|regex| regex.strip_suffix('/'))
+        {
+            let regex = regex::Regex::new(regex)
+                .map_err(|err| format!("invalid external {}: {}", specifier, err))?;
+            ExternalPattern::Regex(regex)
+        } else if let Some(prefix) = specifier.strip_suffix('*') {
+            ExternalPattern::Prefix(prefix.to_string())
+        } else {
+            ExternalPattern::Exact(specifier.to_string())
+        };
+        let (kind, name) = match value.split_once(' ') {
+            Some(("global", name)) => (ExternalKind::Global, name),
+            Some(("commonjs", name)) => (ExternalKind::CommonJs, name),
+            Some(("module", name)) => (ExternalKind::Module, name),
+            Some((kind, _)) => {
+                return Err(format!(
+                "invalid external type {} for {}, expected \"global\", \"commonjs\" or \"module\"",
+                kind, specifier
+            ))
+            }
+            None => (ExternalKind::Global, value),
+        };
+        Ok(Self {
+            pattern,
+            kind,
+            name: name.to_string(),
+        })
+    }
+
+    fn matches(&self, specifier: &str) -> Option<ExternalModule> {
+        let matched = match &self.pattern {
+            ExternalPattern::Exact(name) => name == specifier,
+            ExternalPattern::Prefix(prefix) => specifier.starts_with(prefix.as_str()),
+            ExternalPattern::Regex(regex) => regex.is_match(specifier),
+        };
+        matched.then(|| ExternalModule {
+            kind: self.kind,
+            name: self.name.replace("[request]", specifier),
+        })
+    }
+}
+
+/// Exact names first, then patterns in sorted order.
+fn deserialize_externals<'de, D>(deserializer: D) -> Result<Vec<External>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+    let mut externals = BTreeMap::<String, String>::deserialize(deserializer)?
+        .iter()
+        .map(|(specifier, value)| External::parse(specifier, value))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(D::Error::custom)?;
+    externals.sort_by_key(|external| !matches!(external.pattern, ExternalPattern::Exact(_)));
+    Ok(externals)
+}
+
+/// How modules are named in the runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ModuleIds {
+    /// Root-relative paths, e.g. `./src/index.ts`
+    Path,
+    /// Numbers in discovery order
+    Numeric,
+    /// Short hashes of the root-relative path
+    Hash,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SourceMapMode {
+    /// `bundle.js.map` referenced by a `sourceMappingURL` comment
+    External,
+    /// A base64 data url in the `sourceMappingURL` comment
+    Inline,
+    /// `bundle.js.map` without the comment
+    Hidden,
+}
+
+fn deserialize_sourcemap<'de, D>(deserializer: D) -> Result<Option<SourceMapMode>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Bool(false) => Ok(None),
+        serde_json::Value::Bool(true) => Ok(Some(SourceMapMode::External)),
+        serde_json::Value::String(mode) if mode == "external" => Ok(Some(SourceMapMode::External)),
+        serde_json::Value::String(mode) if mode == "inline" => Ok(Some(SourceMapMode::Inline)),
+        serde_json::Value::String(mode) if mode == "hidden" => Ok(Some(SourceMapMode::Hidden)),
+        value => Err(D::Error::custom(format!(
+            "invalid sourcemap {}, expected a boolean, \"external\", \"inline\" or \"hidden\"",
+            value
+        ))),
+    }
+}
+
+impl Platform {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Platform::Browser => "browser",
+            Platform::Node => "node",
+        }
+    }
+}
+
+impl Mode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Mode::Development => "development",
+            Mode::Production => "production",
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            entry: vec!["index.ts".into()],
+            output: Default::default(),
+            resolve: Default::default(),
+            targets: None,
+            externals: vec![],
+            platform: Platform::default(),
+            define: HashMap::new(),
+            mode: Mode::default(),
+            minify: None,
+            minify_options: Default::default(),
+            tree_shaking: None,
+            scope_hoisting: None,
+            debug: false,
+            module_ids: None,
+            sourcemap: None,
+            public_path: "/".to_string(),
+            cache: true,
+            jsx: Default::default(),
+            css: Default::default(),
+            assets: Default::default(),
+            dev_server: Default::default(),
+        }
+    }
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            path: "dist".into(),
+            filename: "[name].js".to_string(),
+        }
+    }
+}
+
+impl Default for DevServerConfig {
+    fn default() -> Self {
+        Self {
+            port: 3000,
+            hmr: true,
+            react_refresh: false,
+            proxy: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for CssModulesConfig {
+    fn default() -> Self {
+        Self {
+            class_name: "[name]__[local]__[hash]".to_string(),
+            dts: false,
+        }
+    }
+}
+
+impl Default for AssetsConfig {
+    fn default() -> Self {
+        Self {
+            filename: "[name].[hash][ext]".to_string(),
+            inline_limit: 8 * 1024,
+        }
+    }
+}
+
+impl Default for ResolveConfig {
+    fn default() -> Self {
+        Self {
+            alias: BTreeMap::new(),
+            extensions: [".ts", ".tsx", ".js", ".jsx", ".mjs", ".cjs", ".json"]
+                .map(String::from)
+                .to_vec(),
+            conditions: None,
+            main_fields: None,
+            tsconfig: None,
+        }
+    }
+}
+
+impl Config {
+    const FILENAME: &'static str = "mako.config.json";
+
+    /// Loads `path`, or `mako.config.json` in the root when present.
+    fn load(root: &Path, path: Option<&Path>) -> Result<Self, String> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None if root.join(Self::FILENAME).is_file() => root.join(Self::FILENAME),
+            None => return Ok(Self::default()),
+        };
+        let content = std::fs::read_to_string(&path)
+            .map_err(|err| format!("failed to read config {}: {}", path.display(), err))?;
+        let config: Self = serde_json::from_str(&content)
+            .map_err(|err| format!("invalid config {}: {}", path.display(), err))?;
+        config
+            .validate()
+            .map_err(|err| format!("invalid config {}: {}", path.display(), err))?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.entry.is_empty() {
+            return Err("`entry` must not be empty".to_string());
+        }
+        if !self.output.filename.ends_with(".js") {
+            return Err("`output.filename` must end with `.js`".to_string());
+        }
+        if self.assets.filename.contains('/') {
+            return Err("`assets.filename` must not contain `/`".to_string());
+        }
+        if let Some(ext) = self
+            .resolve
+            .extensions
+            .iter()
+            .find(|ext| !ext.starts_with('.'))
+        {
+            return Err(format!(
+                "`resolve.extensions` entry `{}` must start with `.`",
+                ext
+            ));
+        }
+        Ok(())
+    }
+
+    fn minify(&self) -> bool {
+        self.minify.unwrap_or(self.mode == Mode::Production)
+    }
+
+    fn tree_shaking(&self) -> bool {
+        self.tree_shaking.unwrap_or(self.mode == Mode::Production)
+    }
+
+    fn scope_hoisting(&self) -> bool {
+        self.scope_hoisting.unwrap_or(self.mode == Mode::Production)
+    }
+
+    fn extract_css(&self) -> bool {
+        self.css.extract.unwrap_or(self.mode == Mode::Production)
+    }
+
+    fn conditions(&self) -> Vec<String> {
+        self.resolve.conditions.clone().unwrap_or_else(|| {
+            vec![
+                self.platform.as_str().to_string(),
+                self.mode.as_str().to_string(),
+            ]
+        })
+    }
+
+    fn main_fields(&self) -> Vec<String> {
+        self.resolve.main_fields.clone().unwrap_or_else(|| {
+            let fields = match self.platform {
+                Platform::Browser => &["browser", "module", "main"][..],
+                Platform::Node => &["module", "main"],
+            };
+            fields.iter().map(|field| field.to_string()).collect()
+        })
+    }
+
+    fn module_ids(&self) -> ModuleIds {
+        self.module_ids.unwrap_or(match self.mode {
+            Mode::Development => ModuleIds::Path,
+            Mode::Production => ModuleIds::Hash,
+        })
+    }
+
+    /// `define` with `process.env.NODE_ENV` derived from the mode, as code strings.
+    fn define(&self) -> HashMap<String, String> {
+        let mut define = HashMap::from([(
+            "process.env.NODE_ENV".to_string(),
+            format!("\"{}\"", self.mode.as_str()),
+        )]);
+        self.define.iter().for_each(|(key, value)| {
+            let code = match value {
+                serde_json::Value::String(code) => code.clone(),
+                value => value.to_string(),
+            };
+            define.insert(key.clone(), code);
+        });
+        define
+    }
+}
+
+/////////////////////////////////////////
+// Errors
+
+#[derive(Debug)]
+enum CompileError {
+    /// Every error found while building the module graph
+    Build(Vec<BuildError>),
+    Generate(GenerateError),
+}
+
+#[derive(Debug)]
+enum BuildError {
+    Load {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    /// `message` holds the rendered code frames of all syntax errors in the file
+    Parse { path: PathBuf, message: String },
+    Resolve {
+        importer: PathBuf,
+        line: usize,
+        col: usize,
+        kind: DependencyKind,
+        specifier: String,
+        message: String,
+    },
+}
+
+#[derive(Debug)]
+enum GenerateError {
+    Codegen {
+        path: String,
+        error: std::io::Error,
+    },
+    /// The rendered bundle could not be parsed back for minification
+    Minify {
+        message: String,
+    },
+    Write {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::Build(errors) => {
+                writeln!(f, "build failed with {} error(s)", errors.len())?;
+                errors
+                    .iter()
+                    .try_for_each(|error| write!(f, "\n{}\n", error))
+            }
+            CompileError::Generate(error) => write!(f, "generate failed: {}", error),
+        }
+    }
+}
+
+impl BuildError {
+    /// The module that failed to build.
+    fn module(&self) -> &Path {
+        match self {
+            BuildError::Load { path, .. } | BuildError::Parse { path, .. } => path,
+            BuildError::Resolve { importer, .. } => importer,
+        }
+    }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::Load { path, error } => {
+                write!(f, "failed to load {}: {}", path.display(), error)
+            }
+            BuildError::Parse { path, message } => {
+                write!(f, "failed to parse {}\n{}", path.display(), message)
+            }
+            BuildError::Resolve {
+                importer,
+                line,
+                col,
+                kind,
+                specifier,
+                message,
+            } => write!(
+                f,
+                "failed to resolve {} '{}' at {}:{}:{}: {}",
+                kind,
+                specifier,
+                importer.display(),
+                line,
+                col,
+                message
+            ),
+        }
+    }
+}
+
+impl fmt::Display for GenerateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GenerateError::Codegen { path, error } => {
+                write!(f, "failed to generate code for {}: {}", path, error)
+            }
+            GenerateError::Minify { message } => {
+                write!(f, "failed to minify the bundle: {}", message)
+            }
+            GenerateError::Write { path, error } => {
+                write!(f, "failed to write {}: {}", path.display(), error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+impl std::error::Error for BuildError {}
+impl std::error::Error for GenerateError {}
+
+/// Renders parser errors as code frames against the source map.
+fn syntax_error(path: &Path, errors: Vec<ParserError>, cm: Lrc<SourceMap>) -> BuildError {
+    let message = try_with_handler(cm, Default::default(), |handler| {
+        errors
+            .into_iter()
+            .for_each(|error| error.into_diagnostic(handler).emit());
+        Ok(())
+    })
+    .err()
+    .map(|error| error.to_string())
+    .unwrap_or_default();
+    BuildError::Parse {
+        path: path.to_path_buf(),
+        message,
+    }
+}
+
+/////////////////////////////////////////
+// Build Stage
+
+/// Shared by the build workers, a path is only ever built once.
+struct BuildState {
+    context: Arc<Context>,
+    /// Paths already scheduled, including failed and in-flight ones
+    seen: Mutex<HashSet<String>>,
+    modules: Mutex<HashMap<String, Module>>,
+    errors: Mutex<Vec<BuildError>>,
+    timings: BuildTimings,
+}
+
+/// Time spent in each phase, summed over all workers.
+#[derive(Default)]
+struct BuildTimings {
+    load: AtomicU64,
+    parse: AtomicU64,
+    transform: AtomicU64,
+    analyze: AtomicU64,
+    resolve: AtomicU64,
+    /// Modules loaded from the cache
+    cached: AtomicU64,
+}
+
+impl BuildTimings {
+    fn measure<T>(phase: &AtomicU64, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let ret = f();
+        phase.fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        ret
+    }
+
+    fn report(&self, modules: usize, elapsed: Duration) {
+        let ms = |phase: &AtomicU64| phase.load(Ordering::Relaxed) as f64 / 1000.0;
+        eprintln!(
+            "build: {} modules ({} cached) in {:.2}ms on {} threads (load {:.2}ms, parse {:.2}ms, transform {:.2}ms, analyze {:.2}ms, resolve {:.2}ms)",
+            modules,
+            self.cached.load(Ordering::Relaxed),
+            elapsed.as_secs_f64() * 1000.0,
+            rayon::current_num_threads(),
+            ms(&self.load),
+            ms(&self.parse),
+            ms(&self.transform),
+            ms(&self.analyze),
+            ms(&self.resolve),
+        );
+    }
+}
+
+struct BuildParams {
+    entries: Vec<PathBuf>,
+    context: Arc<Context>,
+}
+
+/// Builds the module graph on the rayon thread pool, each module scheduling
+/// its dependencies as soon as they are resolved.
+fn build(params: BuildParams) -> Result<ModuleGraph, Vec<BuildError>> {
+    let start = Instant::now();
+    let state = BuildState {
+        context: params.context,
+        seen: Default::default(),
+        modules: Default::default(),
+        errors: Default::default(),
+        timings: Default::default(),
+    };
+    rayon::scope(|scope| {
+        params
+            .entries
+            .iter()
+            .for_each(|entry| schedule_build(scope, entry.clone(), &state));
     });
-    let mut module_graph = build(BuildParams {
-        path: root.join("index.ts"),
-        context: context.clone(),
+    let modules = discovery_order(state.modules.into_inner().unwrap(), &params.entries);
+    if state.context.config.debug {
+        state.timings.report(modules.len(), start.elapsed());
+    }
+    let mut errors = state.errors.into_inner().unwrap();
+    if !errors.is_empty() {
+        // workers finish in any order
+        errors.sort_by_cached_key(|error| error.to_string());
+        return Err(errors);
+    }
+    Ok(ModuleGraph { modules })
+}
+
+/// Orders modules as a breadth-first walk from the entries would have found
+/// them, whatever order the workers finished in.
+fn discovery_order(
+    mut modules: HashMap<String, Module>,
+    entries: &[PathBuf],
+) -> IndexMap<String, Module> {
+    let mut ordered = IndexMap::with_capacity(modules.len());
+    let mut queue = entries
+        .iter()
+        .map(|entry| entry.to_string_lossy().to_string())
+        .collect::<std::collections::VecDeque<_>>();
+    while let Some(path) = queue.pop_front() {
+        if let Some(module) = modules.remove(&path) {
+            queue.extend(
+                module
+                    .deps
+                    .iter()
+                    .map(|(_, resolved)| resolved.to_string_lossy().to_string()),
+            );
+            ordered.insert(path, module);
+        }
+    }
+    ordered
+}
+
+fn schedule_build<'s>(scope: &rayon::Scope<'s>, path: PathBuf, state: &'s BuildState) {
+    let key = path.to_string_lossy().to_string();
+    if !state.seen.lock().unwrap().insert(key.clone()) {
+        return;
+    }
+    scope.spawn(move |scope| match build_module(&path, state) {
+        Ok(module) => {
+            module
+                .deps
+                .iter()
+                .for_each(|(_, resolved)| schedule_build(scope, resolved.clone(), state));
+            state.modules.lock().unwrap().insert(key, module);
+        }
+        Err(error) => state.errors.lock().unwrap().push(error),
+    });
+}
+
+/// Loads, parses, transforms and analyzes a module, resolving its
+/// dependencies. Resolve errors are recorded without failing the module.
+fn build_module(path: &Path, state: &BuildState) -> Result<Module, BuildError> {
+    let context = state.context.clone();
+    let timings = &state.timings;
+    // load
+    let Loaded {
+        code: content,
+        mut input_source_map,
+        css,
+        asset,
+        hash,
+        mtime,
+    } = BuildTimings::measure(&timings.load, || load(path, state))?;
+    // stylesheets and assets are quicker to load again than to cache
+    let cache = context
+        .cache
+        .as_ref()
+        .filter(|_| is_script(path) && asset_query(path).is_none());
+    let cached = cache.and_then(|cache| cache.read(path, hash));
+    let (ast, deps) = match cached {
+        Some(cached) => {
+            timings.cached.fetch_add(1, Ordering::Relaxed);
+            input_source_map = cached.source_map.map(String::into_bytes);
+            BuildTimings::measure(&timings.parse, || {
+                parse_transformed(cached.code, path, context.clone())
+            })
+            .map(|ast| {
+                let deps = cached
+                    .deps
+                    .into_iter()
+                    .map(|dep| dep.rebase(&ast))
+                    .collect();
+                (ast, deps)
+            })?
+        }
+        None => {
+            // parse
+            let mut ast =
+                BuildTimings::measure(&timings.parse, || parse(content, path, context.clone()))?;
+            // transform
+            BuildTimings::measure(&timings.transform, || {
+                transform(&mut ast, path, context.clone())
+            });
+            let written = cache.and_then(|cache| {
+                cache.write(
+                    &ast,
+                    path,
+                    hash,
+                    input_source_map.as_deref(),
+                    context.clone(),
+                )
+            });
+            match written {
+                // continue with the cached code, so warm builds give the same output
+                Some((cached_ast, cached)) => {
+                    input_source_map = cached.source_map.map(String::into_bytes);
+                    let deps = cached
+                        .deps
+                        .into_iter()
+                        .map(|dep| dep.rebase(&cached_ast))
+                        .collect();
+                    (cached_ast, deps)
+                }
+                None => {
+                    // analyze_deps
+                    let deps = BuildTimings::measure(&timings.analyze, || {
+                        analyze_deps(&ast, context.clone())
+                    });
+                    (ast, deps)
+                }
+            }
+        }
+    };
+    let (bindings, side_effects) = BuildTimings::measure(&timings.analyze, || {
+        (
+            analyze_bindings(&ast),
+            has_side_effects(path, &ast, context.clone()),
+        )
+    });
+    // resolve
+    let mut resolved_deps = vec![];
+    let mut externals = BTreeMap::new();
+    BuildTimings::measure(&timings.resolve, || {
+        deps.into_iter()
+            .for_each(|dep| match resolve(path, &dep, context.clone()) {
+                Ok(Resolved::Path(resolved)) => resolved_deps.push((dep, resolved)),
+                Ok(Resolved::External(external)) => {
+                    externals.insert(dep.specifier, external);
+                }
+                Err(error) => state.errors.lock().unwrap().push(error),
+            })
+    });
+    Ok(Module {
+        ast,
+        deps: resolved_deps,
+        externals,
+        input_source_map,
+        bindings,
+        side_effects,
+        mtime,
+        css,
+        asset,
+    })
+}
+
+/// A file as the JS code standing for it in the module graph.
+struct Loaded {
+    code: String,
+    input_source_map: Option<Vec<u8>>,
+    css: Option<ExtractedCss>,
+    asset: Option<Asset>,
+    hash: u64,
+    mtime: Option<SystemTime>,
+}
+
+/// Scripts are loaded as is, JSON and stylesheets converted to modules and any
+/// other file, or a file imported with `?raw`, `?url` or `?inline`, is an asset.
+fn load(path: &Path, state: &BuildState) -> Result<Loaded, BuildError> {
+    let context = state.context.clone();
+    let (file, _) = split_query(path);
+    let load_error = |error| BuildError::Load {
+        path: file.to_path_buf(),
+        error,
+    };
+    // before reading, so a write in between shows as a change
+    let mtime = modified(file);
+    let content = std::fs::read(file).map_err(load_error)?;
+    let hash = seahash::hash(&content);
+    let loaded = |code| Loaded {
+        code,
+        input_source_map: None,
+        css: None,
+        asset: None,
+        hash,
+        mtime,
+    };
+    let export_default =
+        |value: String| format!("export default {};", serde_json::Value::from(value));
+    let query = asset_query(path);
+    if query == Some("raw") {
+        let text = String::from_utf8_lossy(&content).into_owned();
+        return Ok(loaded(export_default(text)));
+    }
+    if query.is_some() || !is_script(file) && extension(file) != "css" {
+        let (url, asset) = asset_url(file, query, content, &context);
+        return Ok(Loaded {
+            asset,
+            ..loaded(export_default(url))
+        });
+    }
+    let content = String::from_utf8(content)
+        .map_err(|error| load_error(std::io::Error::new(std::io::ErrorKind::InvalidData, error)))?;
+    match extension(file) {
+        "json" => json_to_module(&content, file, context).map(loaded),
+        "css" => load_css(content, file, state).map(|(code, css)| Loaded {
+            css,
+            ..loaded(code)
+        }),
+        _ => Ok(Loaded {
+            input_source_map: load_input_source_map(&content, file),
+            ..loaded(content)
+        }),
+    }
+}
+
+/// JSON from this size on is emitted as `JSON.parse('...')`, which engines
+/// parse faster than the equivalent object literal.
+const JSON_PARSE_THRESHOLD: usize = 10 * 1024;
+
+/// An ES module exporting the parsed JSON as default export, and each top-level
+/// key that is a valid identifier as a named export so unused keys can be
+/// tree shaken.
+fn json_to_module(content: &str, path: &Path, context: Arc<Context>) -> Result<String, BuildError> {
+    let value = serde_json::from_str::<serde_json::Value>(content)
+        .map_err(|error| json_error(content, path, error, context))?;
+    let is_export = |key: &str| Ident::verify_symbol(key).is_ok();
+    // serializing a `Value` does not fail
+    let json = |value: &serde_json::Value| serde_json::to_string(value).unwrap();
+    let Some(object) = value.as_object() else {
+        return Ok(format!("export default {};", json(&value)));
+    };
+    if content.len() >= JSON_PARSE_THRESHOLD {
+        let mut code = vec![
+            format!("const __json = JSON.parse({});", json(&json(&value).into())),
+            "export default __json;".to_string(),
+        ];
+        // keys shadowing what the module itself uses stay on the default export
+        code.extend(
+            object
+                .keys()
+                .filter(|key| is_export(key) && !matches!(key.as_str(), "JSON" | "__json"))
+                .map(|key| {
+                    format!(
+                        "export const {} = __json[{}];",
+                        key,
+                        json(&key.as_str().into())
+                    )
+                }),
+        );
+        return Ok(code.join("\n"));
+    }
+    let mut code = vec![];
+    let mut props = vec![];
+    object.iter().for_each(|(key, value)| {
+        let name = json(&key.as_str().into());
+        if is_export(key) {
+            code.push(format!("export const {} = {};", key, json(value)));
+            props.push(format!("{}: {}", name, key));
+        } else {
+            props.push(format!("{}: {}", name, json(value)));
+        }
     });
-    generate(&mut module_graph, context.clone());
+    code.push(format!("export default {{ {} }};", props.join(", ")));
+    Ok(code.join("\n"))
+}
+
+/// A parse error with a code frame at the location serde reports.
+fn json_error(
+    content: &str,
+    path: &Path,
+    error: serde_json::Error,
+    context: Arc<Context>,
+) -> BuildError {
+    let file = context.cm.new_source_file(
+        FileName::Custom(path.to_string_lossy().to_string()),
+        content.to_string(),
+    );
+    // serde counts lines and columns from 1, both in bytes
+    let line_start = content
+        .split_inclusive('\n')
+        .take(error.line().saturating_sub(1))
+        .map(str::len)
+        .sum::<usize>();
+    let offset = (line_start + error.column().saturating_sub(1)).min(content.len());
+    let pos = file.start_pos + BytePos(offset as u32);
+    let error = error.to_string();
+    // the location is in the code frame
+    let message = error.split(" at line ").next().unwrap_or(&error);
+    let message = try_with_handler(context.cm.clone(), Default::default(), |handler| {
+        handler
+            .struct_span_err(Span::new(pos, pos, Default::default()), message)
+            .emit();
+        Ok(())
+    })
+    .err()
+    .map(|error| error.to_string())
+    .unwrap_or_default();
+    BuildError::Parse {
+        path: path.to_path_buf(),
+        message,
+    }
+}
+
+/// Loads the map from the trailing `//# sourceMappingURL=` comment, either
+/// inlined as a base64 data url or as a file next to `path`.
+fn load_input_source_map(content: &str, path: &Path) -> Option<Vec<u8>> {
+    let url = content
+        .lines()
+        .rev()
+        .find(|line| !line.trim().is_empty())?
+        .trim()
+        .strip_prefix("//# sourceMappingURL=")?;
+    let map = match url.split_once(";base64,") {
+        Some((prefix, data)) if prefix.starts_with("data:application/json") => {
+            base64::engine::general_purpose::STANDARD.decode(data).ok()
+        }
+        Some(_) => None,
+        None => std::fs::read(path.parent()?.join(url)).ok(),
+    };
+    let map = map.filter(|map| sourcemap::SourceMap::from_slice(map).is_ok());
+    if map.is_none() {
+        eprintln!(
+            "warning: ignoring invalid source map {} referenced by {}",
+            url,
+            path.display()
+        );
+    }
+    map
+}
+
+fn parse(content: String, path: &Path, context: Arc<Context>) -> Result<Ast, BuildError> {
+    let ast = code_to_ast(
+        content,
+        path,
+        syntax(path),
+        context.cm.clone(),
+        &context.comments,
+    )
+    .map_err(|errors| syntax_error(path, errors, context.cm.clone()))?;
+    Ok(GLOBALS.set(&context.globals, || Ast {
+        ast,
+        unresolved_mark: Mark::new(),
+        top_level_mark: Mark::new(),
+    }))
+}
+
+/// Imported by the entries when React Refresh is on.
+const REACT_REFRESH_RUNTIME: &str = "react-refresh/runtime";
+
+fn transform(ast: &mut Ast, path: &Path, context: Arc<Context>) {
+    let unresolved_mark = ast.unresolved_mark;
+    let top_level_mark = ast.top_level_mark;
+    let ast = &mut ast.ast;
+    let comments = &context.comments;
+    let is_ts = is_typescript(path);
+    let react_refresh = context.react_refresh() && !is_node_module(path);
+    if react_refresh && context.entries.iter().any(|entry| entry == path) {
+        // the runtime sets up the refresh runtime before any module runs
+        ast.body.insert(
+            0,
+            ModuleItem::ModuleDecl(ModuleDecl::Import(ImportDecl {
+                span: DUMMY_SP,
+                specifiers: vec![],
+                src: Box::new(Str::from(REACT_REFRESH_RUNTIME)),
+                type_only: false,
+                with: None,
+            })),
+        );
+    }
+    GLOBALS.set(&context.globals, || {
+        // helpers are inlined here so the ones used by preset_env survive until codegen
+        HELPERS.set(&Helpers::new(false), || {
+            let resolver = resolver(unresolved_mark, top_level_mark, is_ts);
+            let development = context.config.mode == Mode::Development;
+            let jsx = &context.config.jsx;
+            // before strip, so imports only used by classic JSX are kept
+            let react = Optional::new(
+                react::react(
+                    context.cm.clone(),
+                    Some(comments),
+                    react::Options {
+                        runtime: Some(match jsx.runtime {
+                            JsxRuntime::Automatic => react::Runtime::Automatic,
+                            JsxRuntime::Classic => react::Runtime::Classic,
+                        }),
+                        import_source: jsx.import_source.clone(),
+                        pragma: jsx.pragma.clone(),
+                        pragma_frag: jsx.pragma_frag.clone(),
+                        development: Some(development),
+                        refresh: react_refresh.then(Default::default),
+                        ..Default::default()
+                    },
+                    top_level_mark,
+                    unresolved_mark,
+                ),
+                is_jsx(path),
+            );
+            // strip types, enums, namespaces and `import type` before preset_env
+            let strip = Optional::new(typescript::strip(top_level_mark), is_ts);
+            let preset_env = preset_env::preset_env(
+                unresolved_mark,
+                Some(comments),
+                preset_env::Config {
+                    targets: context.config.targets.clone(),
+                    path: context.root.clone(),
+                    ..Default::default()
+                },
+                Default::default(),
+                &mut Default::default(),
+            );
+            let body = ast.body.take();
+            let module = SwcModule {
+                span: ast.span,
+                shebang: ast.shebang.clone(),
+                body,
+            };
+            // the typescript pass only accepts `Program` as its entry
+            let mut program = chain!(resolver, react, strip).fold_program(Program::Module(module));
+            // after strip, so `declare const` does not count as a binding
+            program.visit_mut_with(&mut DefineReplacer {
+                defines: &context.defines,
+                bindings: collect_decls(&program),
+            });
+            let program = chain!(preset_env, inject_helpers(unresolved_mark)).fold_program(program);
+            ast.body = program.expect_module().body;
+        });
+    });
+}
+
+/// Replaces `define` keys, like `process.env.NODE_ENV`, that are not shadowed
+/// by a local binding.
+struct DefineReplacer<'a> {
+    defines: &'a HashMap<String, Expr>,
+    bindings: AHashSet<Id>,
+}
+
+impl DefineReplacer<'_> {
+    fn dotted_name(&self, expr: &Expr) -> Option<String> {
+        match expr {
+            Expr::Ident(ident) if !self.bindings.contains(&ident.to_id()) => {
+                Some(ident.sym.to_string())
+            }
+            Expr::Member(MemberExpr {
+                obj,
+                prop: MemberProp::Ident(prop),
+                ..
+            }) => self
+                .dotted_name(obj)
+                .map(|obj| format!("{}.{}", obj, prop.sym)),
+            _ => None,
+        }
+    }
+}
+
+impl VisitMut for DefineReplacer<'_> {
+    fn visit_mut_expr(&mut self, expr: &mut Expr) {
+        if let Some(replacement) = self
+            .dotted_name(expr)
+            .and_then(|name| self.defines.get(&name))
+        {
+            *expr = replacement.clone();
+            return;
+        }
+        expr.visit_mut_children_with(self);
+    }
+}
+
+impl fmt::Display for DependencyKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DependencyKind::Static => "import",
+            DependencyKind::ReExport => "re-export",
+            DependencyKind::Dynamic => "dynamic import",
+            DependencyKind::Require => "require",
+            DependencyKind::Worker => "worker",
+            DependencyKind::Url => "url",
+            DependencyKind::HotAccept => "hot accept",
+        })
+    }
+}
+
+fn analyze_deps(ast: &Ast, context: Arc<Context>) -> Vec<Dependency> {
+    let mut analyzer = DepsAnalyzer {
+        deps: vec![],
+        unresolved_mark: ast.unresolved_mark,
+    };
+    GLOBALS.set(&context.globals, || ast.ast.visit_with(&mut analyzer));
+    analyzer.deps
+}
+
+struct DepsAnalyzer {
+    deps: Vec<Dependency>,
+    unresolved_mark: Mark,
+}
+
+impl DepsAnalyzer {
+    fn add(&mut self, src: &Str, kind: DependencyKind) {
+        self.deps.push(Dependency {
+            specifier: src.value.to_string(),
+            kind,
+            span: src.span,
+            origin: None,
+        });
+    }
+
+    /// `module.hot.accept`
+    fn is_hot_accept(&self, callee: &Expr) -> bool {
+        let Expr::Member(MemberExpr {
+            obj,
+            prop: MemberProp::Ident(prop),
+            ..
+        }) = callee
+        else {
+            return false;
+        };
+        let Expr::Member(MemberExpr {
+            obj: module,
+            prop: MemberProp::Ident(hot),
+            ..
+        }) = &**obj
+        else {
+            return false;
+        };
+        &*prop.sym == "accept"
+            && &*hot.sym == "hot"
+            && is_unresolved_ident(module, "module", self.unresolved_mark)
+    }
+}
+
+impl Visit for DepsAnalyzer {
+    fn visit_import_decl(&mut self, import: &ImportDecl) {
+        self.add(&import.src, DependencyKind::Static);
+    }
+
+    fn visit_export_all(&mut self, export: &ExportAll) {
+        self.add(&export.src, DependencyKind::ReExport);
+    }
+
+    fn visit_named_export(&mut self, export: &NamedExport) {
+        if let Some(src) = &export.src {
+            self.add(src, DependencyKind::ReExport);
+        }
+    }
+
+    fn visit_call_expr(&mut self, call: &CallExpr) {
+        match (&call.callee, first_str_arg(Some(&call.args))) {
+            (Callee::Import(_), Some(src)) => self.add(src, DependencyKind::Dynamic),
+            (Callee::Expr(callee), Some(src))
+                if is_unresolved_ident(callee, "require", self.unresolved_mark) =>
+            {
+                self.add(src, DependencyKind::Require)
+            }
+            (Callee::Expr(callee), _) if self.is_hot_accept(callee) => {
+                match call.args.first().map(|arg| &*arg.expr) {
+                    Some(Expr::Lit(Lit::Str(src))) => self.add(src, DependencyKind::HotAccept),
+                    Some(Expr::Array(array)) => array.elems.iter().flatten().for_each(|elem| {
+                        if let Expr::Lit(Lit::Str(src)) = &*elem.expr {
+                            self.add(src, DependencyKind::HotAccept)
+                        }
+                    }),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+        call.visit_children_with(self);
+    }
+
+    fn visit_new_expr(&mut self, new: &NewExpr) {
+        if let Some(src) = url_arg(new, self.unresolved_mark) {
+            self.add(src, DependencyKind::Url);
+            return;
+        }
+        let is_worker = ["Worker", "SharedWorker"]
+            .iter()
+            .any(|name| is_unresolved_ident(&new.callee, name, self.unresolved_mark));
+        let worker_url = new.args.as_deref().and_then(|args| match args.first() {
+            Some(ExprOrSpread { spread: None, expr }) => match &**expr {
+                Expr::New(url) => url_arg(url, self.unresolved_mark),
+                _ => None,
+            },
+            _ => None,
+        });
+        match worker_url {
+            Some(src) if is_worker => self.add(src, DependencyKind::Worker),
+            _ => new.visit_children_with(self),
+        }
+    }
+}
+
+fn first_str_arg(args: Option<&[ExprOrSpread]>) -> Option<&Str> {
+    match args?.first()? {
+        ExprOrSpread { spread: None, expr } => match &**expr {
+            Expr::Lit(Lit::Str(src)) => Some(src),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_unresolved_ident(expr: &Expr, name: &str, unresolved_mark: Mark) -> bool {
+    matches!(expr, Expr::Ident(ident) if &*ident.sym == name && ident.span.ctxt.outer() == unresolved_mark)
+}
+
+/// The specifier of `new URL('x', import.meta.url)`.
+fn url_arg(new: &NewExpr, unresolved_mark: Mark) -> Option<&Str> {
+    if !is_unresolved_ident(&new.callee, "URL", unresolved_mark) {
+        return None;
+    }
+    let args = new.args.as_deref()?;
+    match args.get(1) {
+        Some(ExprOrSpread { spread: None, expr }) if is_import_meta_url(expr) => {
+            first_str_arg(Some(args))
+        }
+        _ => None,
+    }
+}
+
+fn is_import_meta_url(expr: &Expr) -> bool {
+    matches!(
+        expr,
+        Expr::Member(MemberExpr { obj, prop: MemberProp::Ident(prop), .. })
+            if &*prop.sym == "url"
+                && matches!(&**obj, Expr::MetaProp(MetaPropExpr { kind: MetaPropKind::ImportMeta, .. }))
+    )
+}
+
+/// Resolvers for ES module and CommonJS dependencies, built once per compile
+/// and sharing their cache.
+struct Resolvers {
+    esm: oxc_resolver::Resolver,
+    cjs: oxc_resolver::Resolver,
+}
+
+impl Resolvers {
+    fn new(root: &Path, config: &Config) -> Self {
+        use oxc_resolver::{
+            AliasValue, ResolveOptions, Resolver, TsconfigOptions, TsconfigReferences,
+        };
+        let alias = config
+            .resolve
+            .alias
+            .iter()
+            .map(|(from, to)| {
+                // paths are relative to the root, anything else is a specifier
+                let to = if to.starts_with('.') || to.starts_with('/') {
+                    root.join(to).to_string_lossy().to_string()
+                } else {
+                    to.clone()
+                };
+                (from.clone(), vec![AliasValue::Path(to)])
+            })
+            .collect::<Vec<_>>();
+        let tsconfig = match &config.resolve.tsconfig {
+            Some(tsconfig) => Some(root.join(tsconfig)),
+            None => Some(root.join("tsconfig.json")).filter(|tsconfig| tsconfig.is_file()),
+        };
+        let options = |condition: &str| ResolveOptions {
+            alias: alias.clone(),
+            extensions: config.resolve.extensions.clone(),
+            condition_names: std::iter::once(condition.to_string())
+                .chain(config.conditions())
+                .collect(),
+            main_fields: config.main_fields(),
+            tsconfig: tsconfig.clone().map(|config_file| TsconfigOptions {
+                config_file,
+                references: TsconfigReferences::Auto,
+            }),
+            ..Default::default()
+        };
+        let esm = Resolver::new(options("import"));
+        let cjs = esm.clone_with_options(options("require"));
+        Self { esm, cjs }
+    }
+}
+
+enum Resolved {
+    Path(PathBuf),
+    External(ExternalModule),
+}
+
+/// Configured externals first, then Node built-ins by platform, then the
+/// resolver.
+fn resolve(path: &Path, dep: &Dependency, context: Arc<Context>) -> Result<Resolved, BuildError> {
+    if let Some(external) = context
+        .config
+        .externals
+        .iter()
+        .find_map(|external| external.matches(&dep.specifier))
+    {
+        return Ok(Resolved::External(external));
+    }
+    let builtin = dep
+        .specifier
+        .strip_prefix("node:")
+        .unwrap_or(&dep.specifier);
+    if dep.specifier.starts_with("node:") || oxc_resolver::NODEJS_BUILTINS.contains(&builtin) {
+        return Ok(match context.config.platform {
+            Platform::Node => Resolved::External(ExternalModule {
+                kind: ExternalKind::CommonJs,
+                name: dep.specifier.clone(),
+            }),
+            Platform::Browser => browser_polyfill(builtin)
+                .and_then(|polyfill| resolve_path(path, polyfill, dep, context).ok())
+                .map_or(
+                    Resolved::External(ExternalModule {
+                        kind: ExternalKind::Empty,
+                        name: dep.specifier.clone(),
+                    }),
+                    Resolved::Path,
+                ),
+        });
+    }
+    resolve_path(path, &dep.specifier, dep, context).map(Resolved::Path)
+}
+
+/// The package usually installed as the browser version of a Node built-in.
+fn browser_polyfill(builtin: &str) -> Option<&'static str> {
+    Some(match builtin {
+        "assert" => "assert",
+        "buffer" => "buffer",
+        "constants" => "constants-browserify",
+        "crypto" => "crypto-browserify",
+        "domain" => "domain-browser",
+        "events" => "events",
+        "http" => "stream-http",
+        "https" => "https-browserify",
+        "os" => "os-browserify/browser",
+        "path" => "path-browserify",
+        "process" => "process/browser",
+        "punycode" => "punycode",
+        "querystring" => "querystring-es3",
+        "stream" => "stream-browserify",
+        "string_decoder" => "string_decoder",
+        "timers" => "timers-browserify",
+        "tty" => "tty-browserify",
+        "url" => "url",
+        "util" => "util",
+        "vm" => "vm-browserify",
+        "zlib" => "browserify-zlib",
+        _ => return None,
+    })
+}
+
+fn resolve_path(
+    path: &Path,
+    specifier: &str,
+    dep: &Dependency,
+    context: Arc<Context>,
+) -> Result<PathBuf, BuildError> {
+    let resolver = match dep.kind {
+        DependencyKind::Require => &context.resolvers.cjs,
+        _ => &context.resolvers.esm,
+    };
+    resolver
+        .resolve(path.parent().unwrap(), specifier)
+        .map(|resolved| resolved.full_path())
+        .map_err(|error| {
+            let (line, col) = dep.origin.unwrap_or_else(|| line_col(dep.span, &context));
+            BuildError::Resolve {
+                importer: path.to_path_buf(),
+                line,
+                col,
+                kind: dep.kind,
+                specifier: dep.specifier.clone(),
+                message: error.to_string(),
+            }
+        })
+}
+
+fn line_col(span: Span, context: &Context) -> (usize, usize) {
+    let loc = context.cm.lookup_char_pos(span.lo);
+    (loc.line, loc.col_display + 1)
+}
+
+/////////////////////////////////////////
+// CSS
+
+/// Parses a stylesheet, turning local `@import`s and `url()`s into imports of
+/// the module standing for it and prefixing it for the targets. The module
+/// injects the stylesheet into the page, or it is returned for extraction.
+/// CSS modules get their class names scoped and exported.
+fn load_css(
+    content: String,
+    path: &Path,
+    state: &BuildState,
+) -> Result<(String, Option<ExtractedCss>), BuildError> {
+    let context = state.context.clone();
+    let file = context.cm.new_source_file(
+        FileName::Custom(path.to_string_lossy().to_string()),
+        content,
+    );
+    let mut errors = vec![];
+    let stylesheet = css::parser::parse_file::<css::ast::Stylesheet>(
+        &file,
+        css::parser::parser::ParserConfig {
+            legacy_ie: true,
+            css_modules: is_css_module(path),
+            ..Default::default()
+        },
+        &mut errors,
+    );
+    let mut stylesheet = match stylesheet {
+        Ok(stylesheet) if errors.is_empty() => stylesheet,
+        Ok(_) => return Err(css_syntax_error(path, errors, context)),
+        Err(error) => {
+            errors.push(error);
+            return Err(css_syntax_error(path, errors, context));
+        }
+    };
+    // imported stylesheets come first, in their own module
+    let mut imports = vec![];
+    stylesheet.rules.retain(|rule| {
+        let css::ast::Rule::AtRule(at_rule) = rule else {
+            return true;
+        };
+        let Some(css::ast::AtRulePrelude::ImportPrelude(prelude)) = at_rule.prelude.as_deref()
+        else {
+            return true;
+        };
+        let href = match &*prelude.href {
+            css::ast::ImportHref::Str(str) => Some(&*str.value),
+            css::ast::ImportHref::Url(url) => url_value(url),
+        };
+        let Some(specifier) = href.and_then(css_specifier) else {
+            return true;
+        };
+        let dep = Dependency {
+            specifier,
+            kind: DependencyKind::Static,
+            span: at_rule.span,
+            origin: None,
+        };
+        match resolve_path(path, &dep.specifier, &dep, context.clone()) {
+            Ok(_) => imports.push(dep.specifier),
+            Err(error) => state.errors.lock().unwrap().push(error),
+        }
+        false
+    });
+    let mut urls = CssUrlReplacer {
+        path,
+        state,
+        specifiers: vec![],
+    };
+    stylesheet.visit_mut_with(&mut urls);
+    let exports = is_css_module(path).then(|| {
+        let result = css::modules::compile(
+            &mut stylesheet,
+            CssClassNames {
+                path: relative_path(&context.root, path),
+                pattern: &context.config.css.modules.class_name,
+            },
+        );
+        result
+            .renamed
+            .into_iter()
+            .map(|(local, classes)| (local.to_string(), classes))
+            .collect::<BTreeMap<_, _>>()
+    });
+    stylesheet.visit_mut_with(&mut css::prefixer::prefixer(
+        css::prefixer::options::Options {
+            env: context.config.targets.clone(),
+        },
+    ));
+    let minify = context.config.minify();
+    if minify {
+        css::minifier::minify(&mut stylesheet, Default::default());
+    }
+    let mut module = imports
+        .iter()
+        .chain(&urls.specifiers)
+        .map(|specifier| format!("import {};", serde_json::Value::from(specifier.as_str())))
+        .collect::<Vec<_>>();
+    if let Some(exports) = &exports {
+        module.extend(css_module_exports(exports, path, state));
+        if context.config.css.modules.dts {
+            write_css_module_dts(exports, path);
+        }
+    }
+    if context.config.extract_css() {
+        let (imports, rules) = stylesheet.rules.into_iter().partition(|rule| {
+            matches!(rule, css::ast::Rule::AtRule(at_rule)
+                if matches!(at_rule.prelude.as_deref(), Some(css::ast::AtRulePrelude::ImportPrelude(_))))
+        });
+        let css = ExtractedCss {
+            imports: css_to_code(imports, minify),
+            code: css_to_code(rules, minify),
+        };
+        return Ok((module.join("\n"), Some(css)));
+    }
+    let code = css_to_code(stylesheet.rules, minify);
+    module.push(format!(
+        r#"if (typeof document !== "undefined") {{
+  const style = document.createElement("style");
+  style.textContent = {};
+  document.head.appendChild(style);
+  if (module.hot) {{
+    module.hot.dispose(() => style.remove());
+  }}
+}}"#,
+        serde_json::Value::from(code)
+    ));
+    // importers of a CSS module have to see its new class names
+    if exports.is_none() {
+        module.push("if (module.hot) {\n  module.hot.accept();\n}".to_string());
+    }
+    Ok((module.join("\n"), None))
+}
+
+fn is_css_module(path: &Path) -> bool {
+    split_query(path)
+        .0
+        .to_string_lossy()
+        .ends_with(".module.css")
+}
+
+/// Scoped class names from `css.modules.className`.
+struct CssClassNames<'a> {
+    /// Relative to the root, so names are the same on every machine
+    path: String,
+    pattern: &'a str,
+}
+
+impl css::modules::TransformConfig for CssClassNames<'_> {
+    fn new_name_for(&self, local: &JsWord) -> JsWord {
+        let name = Path::new(&self.path)
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        let name = name.trim_end_matches(".module.css");
+        let hash = seahash::hash(format!("{}:{}", self.path, local).as_bytes());
+        let class_name = self
+            .pattern
+            .replace("[name]", name)
+            .replace("[local]", local)
+            .replace("[hash]", &format!("{:016x}", hash)[..8])
+            .replace(
+                |c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '-',
+                "_",
+            );
+        // class names can't start with a digit
+        match class_name.starts_with(|c: char| c.is_ascii_digit()) {
+            true => format!("_{}", class_name).into(),
+            false => class_name.into(),
+        }
+    }
+}
+
+/// Exports each class name as a string of the scoped names it stands for,
+/// including the ones it `composes`, both by name when it is an identifier
+/// and on the default export.
+fn css_module_exports(
+    exports: &BTreeMap<String, Vec<css::modules::CssClassName>>,
+    path: &Path,
+    state: &BuildState,
+) -> Vec<String> {
+    let context = state.context.clone();
+    let mut code = vec![];
+    let mut composed = BTreeMap::new();
+    let mut values = BTreeMap::new();
+    exports.iter().for_each(|(local, classes)| {
+        let mut names = vec![];
+        let mut imported = vec![];
+        classes.iter().for_each(|class| match class {
+            css::modules::CssClassName::Local { name }
+            | css::modules::CssClassName::Global { name } => names.push(name.value.to_string()),
+            css::modules::CssClassName::Import { name, from } => {
+                let specifier = from.strip_prefix('~').unwrap_or(from).to_string();
+                let next = composed.len();
+                // `None` when it failed to resolve
+                let ident = composed.entry(specifier.clone()).or_insert_with(|| {
+                    let dep = Dependency {
+                        specifier: specifier.clone(),
+                        kind: DependencyKind::Static,
+                        span: name.span,
+                        origin: None,
+                    };
+                    if let Err(error) = resolve_path(path, &specifier, &dep, context.clone()) {
+                        state.errors.lock().unwrap().push(error);
+                        return None;
+                    }
+                    code.push(format!(
+                        "import __composes_{} from {};",
+                        next,
+                        serde_json::Value::from(specifier.as_str())
+                    ));
+                    Some(format!("__composes_{}", next))
+                });
+                let Some(ident) = ident else {
+                    return;
+                };
+                imported.push(format!(
+                    "{}[{}]",
+                    ident,
+                    serde_json::Value::from(&*name.value)
+                ));
+            }
+        });
+        let names = serde_json::Value::from(names.join(" ")).to_string();
+        let value = match imported.is_empty() {
+            true => names,
+            false => format!("[{}, {}].join(\" \")", names, imported.join(", ")),
+        };
+        values.insert(local, value);
+    });
+    let is_export =
+        |local: &str| Ident::verify_symbol(local).is_ok() && !local.starts_with("__composes_");
+    let mut props = vec![];
+    values.into_iter().for_each(|(local, value)| {
+        let key = serde_json::Value::from(local.as_str());
+        if is_export(local) {
+            code.push(format!("export const {} = {};", local, value));
+            props.push(format!("{}: {}", key, local));
+        } else {
+            props.push(format!("{}: {}", key, value));
+        }
+    });
+    code.push(format!("export default {{ {} }};", props.join(", ")));
+    code
+}
+
+/// Declares the class names for TypeScript in `<file>.d.ts`, only writing it
+/// when changed so watching does not rebuild on every build.
+fn write_css_module_dts(exports: &BTreeMap<String, Vec<css::modules::CssClassName>>, path: &Path) {
+    let mut dts = vec!["declare const styles: {".to_string()];
+    dts.extend(exports.keys().map(|local| {
+        format!(
+            "  readonly {}: string;",
+            serde_json::Value::from(local.as_str())
+        )
+    }));
+    dts.push("};".to_string());
+    dts.push("export default styles;".to_string());
+    dts.extend(
+        exports
+            .keys()
+            .filter(|local| Ident::verify_symbol(local).is_ok())
+            .map(|local| format!("export declare const {}: string;", local)),
+    );
+    let dts = dts.join("\n") + "\n";
+    let dts_path = PathBuf::from(format!("{}.d.ts", path.display()));
+    if std::fs::read_to_string(&dts_path).map_or(true, |content| content != dts) {
+        if let Err(error) = std::fs::write(&dts_path, dts) {
+            eprintln!("warning: failed to write {}: {}", dts_path.display(), error);
+        }
+    }
+}
+
+fn css_to_code(rules: Vec<css::ast::Rule>, minify: bool) -> String {
+    let mut code = String::new();
+    let mut generator = css::codegen::CodeGenerator::new(
+        css::codegen::writer::basic::BasicCssWriter::new(&mut code, None, Default::default()),
+        css::codegen::CodegenConfig { minify },
+    );
+    let stylesheet = css::ast::Stylesheet {
+        span: DUMMY_SP,
+        rules,
+    };
+    // writing to a `String` does not fail
+    css::codegen::Emit::emit(&mut generator, &stylesheet).unwrap();
+    code
+}
+
+fn url_value(url: &css::ast::Url) -> Option<&str> {
+    match url.value.as_deref()? {
+        css::ast::UrlValue::Str(str) => Some(&str.value),
+        css::ast::UrlValue::Raw(raw) => Some(&raw.value),
+    }
+}
+
+/// The module request for a URL in a stylesheet, which is relative unless it
+/// starts with `~`. Absolute URLs, data URIs and fragments are left alone.
+fn css_specifier(url: &str) -> Option<String> {
+    if url.is_empty()
+        || url.starts_with(['/', '#'])
+        || url.starts_with("data:")
+        || url.contains("://")
+    {
+        return None;
+    }
+    // e.g. the `?#iefix` of font URLs
+    let url = url.split(['?', '#']).next().unwrap_or(url);
+    Some(match url.strip_prefix('~') {
+        Some(request) => request.to_string(),
+        None if url.starts_with("./") || url.starts_with("../") => url.to_string(),
+        None => format!("./{}", url),
+    })
+}
+
+/// Rewrites `url()`s to the URLs of the assets they reference.
+struct CssUrlReplacer<'a> {
+    path: &'a Path,
+    state: &'a BuildState,
+    specifiers: Vec<String>,
+}
+
+impl css::visit::VisitMut for CssUrlReplacer<'_> {
+    fn visit_mut_url(&mut self, url: &mut css::ast::Url) {
+        let Some(specifier) = url_value(url).and_then(css_specifier) else {
+            return;
+        };
+        let context = self.state.context.clone();
+        let dep = Dependency {
+            specifier: specifier.clone(),
+            kind: DependencyKind::Url,
+            span: url.span,
+            origin: None,
+        };
+        let resolved =
+            resolve_path(self.path, &specifier, &dep, context.clone()).and_then(|resolved| {
+                std::fs::read(&resolved)
+                    .map(|content| asset_url(&resolved, None, content, &context).0)
+                    .map_err(|error| BuildError::Load {
+                        path: resolved,
+                        error,
+                    })
+            });
+        match resolved {
+            Ok(asset_url) => {
+                url.value = Some(Box::new(css::ast::UrlValue::Str(css::ast::Str {
+                    span: url.span,
+                    value: asset_url.into(),
+                    raw: None,
+                })));
+                self.specifiers.push(specifier);
+            }
+            Err(error) => self.state.errors.lock().unwrap().push(error),
+        }
+    }
+}
+
+fn css_syntax_error(
+    path: &Path,
+    errors: Vec<css::parser::error::Error>,
+    context: Arc<Context>,
+) -> BuildError {
+    let message = try_with_handler(context.cm.clone(), Default::default(), |handler| {
+        errors
+            .iter()
+            .for_each(|error| error.to_diagnostics(handler).emit());
+        Ok(())
+    })
+    .err()
+    .map(|error| error.to_string())
+    .unwrap_or_default();
+    BuildError::Parse {
+        path: path.to_path_buf(),
+        message,
+    }
+}
+
+/// The stylesheets of the modules left after tree shaking, in the order the
+/// entries import them, as one file named after the entry chunk.
+fn extract_css(
+    module_graph: &ModuleGraph,
+    entry_filename: &str,
+    context: Arc<Context>,
+) -> Option<OutputFile> {
+    fn visit<'a>(
+        path: &'a str,
+        module_graph: &'a ModuleGraph,
+        visited: &mut HashSet<&'a str>,
+        css: &mut Vec<&'a ExtractedCss>,
+    ) {
+        let Some(module) = module_graph.modules.get(path) else {
+            return;
+        };
+        if !visited.insert(path) {
+            return;
+        }
+        module.deps.iter().for_each(|(_, resolved)| {
+            let resolved = module_graph
+                .modules
+                .get_key_value(&*resolved.to_string_lossy())
+                .map(|(key, _)| key.as_str());
+            if let Some(resolved) = resolved {
+                visit(resolved, module_graph, visited, css);
+            }
+        });
+        css.extend(&module.css);
+    }
+    let mut visited = HashSet::new();
+    let mut css = vec![];
+    context.entries.iter().for_each(|entry| {
+        if let Some((entry, _)) = module_graph
+            .modules
+            .get_key_value(&*entry.to_string_lossy())
+        {
+            visit(entry, module_graph, &mut visited, &mut css);
+        }
+    });
+    if css.is_empty() {
+        return None;
+    }
+    let content = css
+        .iter()
+        .map(|css| &css.imports)
+        .chain(css.iter().map(|css| &css.code))
+        .filter(|code| !code.is_empty())
+        .cloned()
+        .collect::<Vec<_>>()
+        .join("\n");
+    Some(OutputFile {
+        filename: css_filename(entry_filename),
+        content: content.into_bytes(),
+    })
+}
+
+fn css_filename(js_filename: &str) -> String {
+    // `output.filename` is validated to end with `.js`
+    format!("{}.css", js_filename.trim_end_matches(".js"))
+}
+
+/////////////////////////////////////////
+// Assets
+
+/// The URL an asset is imported as, a data URI when forced by `?inline` or
+/// when it is small enough and not forced to a file by `?url`. Otherwise the
+/// file is emitted under a content-hashed name.
+fn asset_url(
+    file: &Path,
+    query: Option<&str>,
+    content: Vec<u8>,
+    context: &Context,
+) -> (String, Option<Asset>) {
+    let inline = match query {
+        Some("inline") => true,
+        Some("url") => false,
+        _ => content.len() < context.config.assets.inline_limit,
+    };
+    if inline {
+        let mime = content_type(&file.to_string_lossy())
+            .split(';')
+            .next()
+            .unwrap_or_default();
+        let data = base64::engine::general_purpose::STANDARD.encode(&content);
+        return (format!("data:{};base64,{}", mime, data), None);
+    }
+    let asset = Asset::new(file, content, context);
+    (
+        format!("{}{}", context.config.public_path, asset.filename),
+        Some(asset),
+    )
+}
+
+impl Asset {
+    fn new(file: &Path, content: Vec<u8>, context: &Context) -> Self {
+        let name = file
+            .file_stem()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let ext = match extension(file) {
+            "" => String::new(),
+            ext => format!(".{}", ext),
+        };
+        let hash = format!("{:016x}", seahash::hash(&content));
+        Self {
+            filename: context
+                .config
+                .assets
+                .filename
+                .replace("[name]", &name)
+                .replace("[hash]", &hash[..8])
+                .replace("[ext]", &ext),
+            content: content.into(),
+        }
+    }
+}
+
+/// A URL dependency on an inlined asset becomes its data URI, on an emitted
+/// asset the URL of the file.
+fn asset_module_url(path: &str, module: &Module, context: &Context) -> Option<String> {
+    if let Some(asset) = &module.asset {
+        return Some(output_url(&asset.filename, context));
+    }
+    let (file, query) = split_query(Path::new(path));
+    let is_inlined = match query {
+        Some(query) => query == "inline",
+        None => !is_script(file) && extension(file) != "css",
+    };
+    if !is_inlined {
+        return None;
+    }
+    module.ast.ast.body.iter().find_map(|item| match item {
+        ModuleItem::ModuleDecl(ModuleDecl::ExportDefaultExpr(export)) => match &*export.expr {
+            Expr::Lit(Lit::Str(url)) => Some(url.value.to_string()),
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+/// The URL of an emitted file relative to `import.meta.url`, see
+/// `ImportMetaUrlReplacer`: under the public path in browsers, next to the
+/// bundle in Node.
+fn output_url(filename: &str, context: &Context) -> String {
+    match context.config.platform {
+        Platform::Browser => format!("{}{}", context.config.public_path, filename),
+        Platform::Node => filename.to_string(),
+    }
+}
+
+/////////////////////////////////////////
+// Cache
+
+/// Transformed code and dependencies of modules on disk, so warm builds only
+/// parse the transformed code again. Entries live in a directory named by a
+/// fingerprint of the toolchain and everything in the config that `transform`
+/// or resolving depend on, one per module path. Changed modules overwrite their
+/// entry and only the most recently used fingerprints are kept, so the cache
+/// does not grow with every config edit while development and production
+/// builds keep their own.
+struct Cache {
+    dir: PathBuf,
+}
+
+/// Fingerprint directories kept, including the one in use
+const CACHE_FINGERPRINTS: usize = 4;
+
+/// File touched in a fingerprint directory whenever a build uses it
+const CACHE_USED: &str = "used";
+
+#[derive(Serialize, Deserialize)]
+struct CachedModule {
+    /// Hash of the loaded content `code` was transformed from
+    hash: u64,
+    code: String,
+    /// Map of `code` back to the loaded file, when source maps are enabled
+    source_map: Option<String>,
+    deps: Vec<CachedDependency>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedDependency {
+    specifier: String,
+    kind: DependencyKind,
+    /// Span of the specifier, relative to the start of the module
+    lo: u32,
+    hi: u32,
+    /// Position in the loaded file, for errors
+    line: usize,
+    col: usize,
+}
+
+impl CachedDependency {
+    fn rebase(self, ast: &Ast) -> Dependency {
+        let start = ast.ast.span.lo;
+        Dependency {
+            specifier: self.specifier,
+            kind: self.kind,
+            span: Span::new(
+                start + BytePos(self.lo),
+                start + BytePos(self.hi),
+                Default::default(),
+            ),
+            origin: Some((self.line, self.col)),
+        }
+    }
+}
+
+impl Cache {
+    /// `None` when the project has no `node_modules` or the cache directory
+    /// can't be created.
+    fn new(root: &Path, config: &Config, entries: &[PathBuf], hmr: bool) -> Option<Self> {
+        // the map variant iterates in random order
+        let targets = match &config.targets {
+            Some(preset_env::Targets::HashMap(targets)) => {
+                format!("{:?}", targets.iter().collect::<BTreeMap<_, _>>())
+            }
+            targets => format!("{:?}", targets),
+        };
+        // browserslist is read from these without `targets`
+        let browserslist = ["package.json", ".browserslistrc"]
+            .map(|file| std::fs::read_to_string(root.join(file)).unwrap_or_default());
+        let fingerprint = format!(
+            "{} {} {:?} {:?} {:?} {} {:?} {:?} {:?} {:?} {:?} {}",
+            env!("CARGO_PKG_VERSION"),
+            swc_core::SWC_CORE_VERSION,
+            config.mode,
+            config.define.iter().collect::<BTreeMap<_, _>>(),
+            config.jsx,
+            targets,
+            browserslist,
+            config.resolve,
+            config.platform,
+            config.sourcemap.is_some(),
+            entries,
+            hmr && config.dev_server.react_refresh,
+        );
+        let node_modules = root.join("node_modules");
+        if !node_modules.is_dir() {
+            return None;
+        }
+        let base = node_modules.join(".cache/mako");
+        let dir = base.join(format!("{:016x}", seahash::hash(fingerprint.as_bytes())));
+        if let Err(err) = std::fs::create_dir_all(&dir) {
+            eprintln!(
+                "warning: cache disabled, failed to create {}: {}",
+                dir.display(),
+                err
+            );
+            return None;
+        }
+        // marks the fingerprint as used by this build
+        let _ = std::fs::write(dir.join(CACHE_USED), []);
+        Self::evict(&base, &dir);
+        Some(Self { dir })
+    }
+
+    /// Removes all but the most recently used fingerprints other than `dir`,
+    /// which may still be used by a running server.
+    fn evict(base: &Path, dir: &Path) {
+        let Ok(entries) = std::fs::read_dir(base) else {
+            return;
+        };
+        let mut others = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path != dir)
+            .map(|path| {
+                let used = std::fs::metadata(path.join(CACHE_USED))
+                    .and_then(|metadata| metadata.modified())
+                    .ok();
+                (used, path)
+            })
+            .collect::<Vec<_>>();
+        // most recently used first, entries without a mark last
+        others.sort_by(|a, b| b.cmp(a));
+        others
+            .into_iter()
+            .skip(CACHE_FINGERPRINTS - 1)
+            .for_each(|(_, path)| {
+                let _ = if path.is_dir() {
+                    std::fs::remove_dir_all(path)
+                } else {
+                    std::fs::remove_file(path)
+                };
+            });
+    }
+
+    fn file(&self, path: &Path) -> PathBuf {
+        let key = path.to_string_lossy();
+        self.dir
+            .join(format!("{:016x}.json", seahash::hash(key.as_bytes())))
+    }
+
+    fn read(&self, path: &Path, hash: u64) -> Option<CachedModule> {
+        let content = std::fs::read(self.file(path)).ok()?;
+        let cached = serde_json::from_slice::<CachedModule>(&content).ok()?;
+        (cached.hash == hash).then_some(cached)
+    }
+
+    /// Prints the transformed module and parses it back, returning the parsed
+    /// module with what was written. `None` when the printed code does not
+    /// parse, the module is then not cached.
+    fn write(
+        &self,
+        ast: &Ast,
+        path: &Path,
+        hash: u64,
+        input_source_map: Option<&[u8]>,
+        context: Arc<Context>,
+    ) -> Option<(Ast, CachedModule)> {
+        // positions in the printed code don't match the loaded file
+        let origins = analyze_deps(ast, context.clone());
+        let mut ast = ast.clone();
+        GLOBALS.set(&context.globals, || {
+            // names only differing by syntax context would collide once printed
+            ast.ast.visit_mut_with(&mut hygiene());
+            ast.ast.visit_mut_with(&mut fixer(Some(&context.comments)));
+        });
+        let (code, mappings) = ast_to_code(&ast, context.clone()).ok()?;
+        let source_map = context.config.sourcemap.map(|_| {
+            let input_source_map =
+                input_source_map.and_then(|map| sourcemap::SourceMap::from_slice(map).ok());
+            let mut buf = vec![];
+            // serializing into a `Vec` does not fail
+            context
+                .cm
+                .build_source_map_with_config(&mappings, input_source_map.as_ref(), SourceMapConfig)
+                .to_writer(&mut buf)
+                .unwrap();
+            // source maps are JSON
+            String::from_utf8(buf).unwrap()
+        });
+        let ast = parse_transformed(code.clone(), path, context.clone()).ok()?;
+        let start = ast.ast.span.lo;
+        let deps = analyze_deps(&ast, context.clone())
+            .into_iter()
+            .enumerate()
+            .map(|(i, dep)| {
+                let origin = origins
+                    .get(i)
+                    .filter(|origin| origin.specifier == dep.specifier && origin.kind == dep.kind)
+                    .unwrap_or(&dep);
+                let (line, col) = line_col(origin.span, &context);
+                CachedDependency {
+                    line,
+                    col,
+                    specifier: dep.specifier,
+                    kind: dep.kind,
+                    lo: (dep.span.lo - start).0,
+                    hi: (dep.span.hi - start).0,
+                }
+            })
+            .collect();
+        let cached = CachedModule {
+            hash,
+            code,
+            source_map,
+            deps,
+        };
+        // a failed write only costs a transform on the next build; the rename
+        // keeps concurrent builds from reading half written entries
+        let file = self.file(path);
+        let tmp = file.with_extension(format!("{}.tmp", std::process::id()));
+        if let Ok(content) = serde_json::to_vec(&cached) {
+            if std::fs::write(&tmp, content).is_ok() {
+                let _ = std::fs::rename(&tmp, &file);
+            }
+        }
+        Some((ast, cached))
+    }
+}
+
+/// Parses code `transform` already ran on, as plain JavaScript.
+fn parse_transformed(code: String, path: &Path, context: Arc<Context>) -> Result<Ast, BuildError> {
+    let mut ast = code_to_ast(
+        code,
+        path,
+        Syntax::Es(Default::default()),
+        context.cm.clone(),
+        &context.comments,
+    )
+    .map_err(|errors| syntax_error(path, errors, context.cm.clone()))?;
+    Ok(GLOBALS.set(&context.globals, || {
+        let unresolved_mark = Mark::new();
+        let top_level_mark = Mark::new();
+        ast.visit_mut_with(&mut resolver(unresolved_mark, top_level_mark, false));
+        Ast {
+            ast,
+            unresolved_mark,
+            top_level_mark,
+        }
+    }))
+}
+
+/////////////////////////////////////////
+// Tree Shaking
+
+/// Import and export bindings of a module, keyed by dependency specifier.
+#[derive(Debug, Clone, Default)]
+struct ModuleBindings {
+    /// Whether the module uses `import`/`export`, CommonJS modules are never shaken
+    is_esm: bool,
+    /// Names exported by the module's own declarations
+    local_exports: HashSet<String>,
+    /// `export { imported as exported } from 'x'`
+    reexports: Vec<(String, Vec<(String, String)>)>,
+    /// `export * from 'x'`
+    star_reexports: Vec<String>,
+    /// Names imported from each dependency, `import 'x'` imports none
+    imports: Vec<(String, UsedExports)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum UsedExports {
+    All,
+    Names(HashSet<String>),
+}
+
+impl UsedExports {
+    fn empty() -> Self {
+        UsedExports::Names(HashSet::new())
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        match self {
+            UsedExports::All => true,
+            UsedExports::Names(names) => names.contains(name),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        matches!(self, UsedExports::Names(names) if names.is_empty())
+    }
+
+    /// Returns whether anything was added.
+    fn extend(&mut self, other: &UsedExports) -> bool {
+        match (&mut *self, other) {
+            (UsedExports::All, _) => false,
+            (_, UsedExports::All) => {
+                *self = UsedExports::All;
+                true
+            }
+            (UsedExports::Names(names), UsedExports::Names(other)) => {
+                let len = names.len();
+                names.extend(other.iter().cloned());
+                names.len() != len
+            }
+        }
+    }
+}
+
+fn analyze_bindings(ast: &Ast) -> ModuleBindings {
+    let mut bindings = ModuleBindings::default();
+    ast.ast.body.iter().for_each(|item| {
+        let ModuleItem::ModuleDecl(decl) = item else {
+            return;
+        };
+        bindings.is_esm = true;
+        match decl {
+            ModuleDecl::Import(import) => {
+                let names = import
+                    .specifiers
+                    .iter()
+                    .map(|specifier| match specifier {
+                        ImportSpecifier::Named(named) => UsedExports::Names(HashSet::from([named
+                            .imported
+                            .as_ref()
+                            .map(export_name)
+                            .unwrap_or_else(|| named.local.sym.to_string())])),
+                        ImportSpecifier::Default(_) => {
+                            UsedExports::Names(HashSet::from(["default".to_string()]))
+                        }
+                        ImportSpecifier::Namespace(_) => UsedExports::All,
+                    })
+                    .fold(UsedExports::empty(), |mut used, names| {
+                        used.extend(&names);
+                        used
+                    });
+                bindings.imports.push((import.src.value.to_string(), names));
+            }
+            ModuleDecl::ExportDecl(export) => {
+                bindings.local_exports.extend(decl_names(&export.decl));
+            }
+            ModuleDecl::ExportDefaultDecl(_) | ModuleDecl::ExportDefaultExpr(_) => {
+                bindings.local_exports.insert("default".to_string());
+            }
+            ModuleDecl::ExportNamed(export) => {
+                let names = export.specifiers.iter().map(|specifier| match specifier {
+                    ExportSpecifier::Named(named) => (
+                        export_name(&named.orig),
+                        export_name(named.exported.as_ref().unwrap_or(&named.orig)),
+                    ),
+                    ExportSpecifier::Default(default) => {
+                        ("default".to_string(), default.exported.sym.to_string())
+                    }
+                    ExportSpecifier::Namespace(namespace) => {
+                        ("*".to_string(), export_name(&namespace.name))
+                    }
+                });
+                match &export.src {
+                    Some(src) => bindings
+                        .reexports
+                        .push((src.value.to_string(), names.collect())),
+                    None => bindings
+                        .local_exports
+                        .extend(names.map(|(_, exported)| exported)),
+                }
+            }
+            ModuleDecl::ExportAll(export) => {
+                bindings.star_reexports.push(export.src.value.to_string());
+            }
+            _ => {}
+        }
+    });
+    bindings
+}
+
+fn export_name(name: &ModuleExportName) -> String {
+    match name {
+        ModuleExportName::Ident(ident) => ident.sym.to_string(),
+        ModuleExportName::Str(str) => str.value.to_string(),
+    }
+}
+
+fn decl_names(decl: &Decl) -> Vec<String> {
+    let ids: Vec<Id> = match decl {
+        Decl::Class(class) => vec![class.ident.to_id()],
+        Decl::Fn(function) => vec![function.ident.to_id()],
+        decl => find_pat_ids(decl),
+    };
+    ids.into_iter().map(|(sym, _)| sym.to_string()).collect()
+}
+
+/// Whether evaluating the module has observable effects, honouring the
+/// `sideEffects` field of the closest `package.json`.
+fn has_side_effects(path: &Path, ast: &Ast, context: Arc<Context>) -> bool {
+    if let Some(side_effects) = package_side_effects(path) {
+        return side_effects;
+    }
+    // the module only imports what its stylesheet references
+    if extension(path) == "css" && asset_query(path).is_none() {
+        return true;
+    }
+    GLOBALS.set(&context.globals, || {
+        let purity = Purity {
+            comments: &context.comments,
+            unresolved_mark: ast.unresolved_mark,
+        };
+        !ast.ast.body.iter().all(|item| match item {
+            ModuleItem::ModuleDecl(ModuleDecl::ExportDecl(export)) => {
+                purity.is_pure_decl(&export.decl)
+            }
+            ModuleItem::ModuleDecl(ModuleDecl::ExportDefaultExpr(export)) => {
+                purity.is_pure_expr(&export.expr)
+            }
+            ModuleItem::ModuleDecl(ModuleDecl::ExportDefaultDecl(export)) => match &export.decl {
+                DefaultDecl::Class(class) => purity.is_pure_class(&class.class),
+                _ => true,
+            },
+            ModuleItem::ModuleDecl(_) => true,
+            ModuleItem::Stmt(stmt) => purity.is_pure_stmt(stmt),
+        })
+    })
+}
+
+fn package_side_effects(path: &Path) -> Option<bool> {
+    let (dir, package_json) = path.ancestors().skip(1).find_map(|dir| {
+        let content = std::fs::read_to_string(dir.join("package.json")).ok()?;
+        Some((
+            dir,
+            serde_json::from_str::<serde_json::Value>(&content).ok()?,
+        ))
+    })?;
+    match package_json.get("sideEffects")? {
+        serde_json::Value::Bool(side_effects) => Some(*side_effects),
+        serde_json::Value::Array(patterns) => {
+            let relative = path.strip_prefix(dir).ok()?.to_string_lossy().to_string();
+            Some(
+                patterns
+                    .iter()
+                    .filter_map(|pattern| pattern.as_str())
+                    .any(|pattern| side_effects_match(pattern, &relative)),
+            )
+        }
+        _ => None,
+    }
+}
+
+/// Matches a `sideEffects` pattern against a path relative to the package,
+/// patterns without `/` match the file name in any directory.
+fn side_effects_match(pattern: &str, relative: &str) -> bool {
+    let pattern = pattern.trim_start_matches("./");
+    if pattern.contains('/') {
+        glob_match(pattern, relative)
+    } else {
+        glob_match(&format!("**/{}", pattern), relative)
+    }
+}
+
+/// Matches `*`, `**` and `?` wildcards against a `/` separated path.
+fn glob_match(pattern: &str, path: &str) -> bool {
+    match pattern.strip_prefix("**/") {
+        Some(rest) => {
+            glob_match(rest, path)
+                || path
+                    .split_once('/')
+                    .map_or(false, |(_, path)| glob_match(pattern, path))
+        }
+        None => match (pattern.chars().next(), path.chars().next()) {
+            (None, None) => true,
+            (Some('*'), _) if pattern.starts_with("**") => {
+                glob_match(&pattern[2..], path)
+                    || path
+                        .chars()
+                        .next()
+                        .map_or(false, |c| glob_match(pattern, &path[c.len_utf8()..]))
+            }
+            (Some('*'), _) => {
+                glob_match(&pattern[1..], path)
+                    || path
+                        .chars()
+                        .next()
+                        .filter(|c| *c != '/')
+                        .map_or(false, |c| glob_match(pattern, &path[c.len_utf8()..]))
+            }
+            (Some('?'), Some(c)) if c != '/' => glob_match(&pattern[1..], &path[c.len_utf8()..]),
+            (Some(p), Some(c)) if p == c => {
+                glob_match(&pattern[p.len_utf8()..], &path[c.len_utf8()..])
+            }
+            _ => false,
+        },
+    }
+}
+
+/// Conservative side-effect analysis, `/*#__PURE__*/` calls count as pure.
+struct Purity<'a> {
+    comments: &'a SwcComments,
+    unresolved_mark: Mark,
+}
+
+impl Purity<'_> {
+    fn is_pure_stmt(&self, stmt: &Stmt) -> bool {
+        match stmt {
+            Stmt::Empty(_) => true,
+            Stmt::Decl(decl) => self.is_pure_decl(decl),
+            Stmt::Expr(expr) => self.is_pure_expr(&expr.expr),
+            _ => false,
+        }
+    }
+
+    fn is_pure_decl(&self, decl: &Decl) -> bool {
+        match decl {
+            Decl::Fn(_) => true,
+            Decl::Class(class) => self.is_pure_class(&class.class),
+            Decl::Var(var) => var
+                .decls
+                .iter()
+                .all(|decl| self.is_pure_var_declarator(decl)),
+            _ => false,
+        }
+    }
+
+    fn is_pure_var_declarator(&self, decl: &VarDeclarator) -> bool {
+        // destructuring may run getters
+        matches!(decl.name, Pat::Ident(_))
+            && decl
+                .init
+                .as_ref()
+                .map_or(true, |init| self.is_pure_expr(init))
+    }
+
+    fn is_pure_class(&self, class: &Class) -> bool {
+        class
+            .super_class
+            .as_ref()
+            .map_or(true, |super_class| self.is_pure_expr(super_class))
+            && class.body.iter().all(|member| match member {
+                ClassMember::StaticBlock(_) => false,
+                ClassMember::ClassProp(prop) if prop.is_static => prop
+                    .value
+                    .as_ref()
+                    .map_or(true, |value| self.is_pure_expr(value)),
+                _ => true,
+            })
+    }
+
+    fn is_pure_expr(&self, expr: &Expr) -> bool {
+        match expr {
+            Expr::Lit(_) | Expr::Fn(_) | Expr::Arrow(_) | Expr::This(_) => true,
+            // reading an undeclared global throws
+            Expr::Ident(ident) => {
+                ident.span.ctxt.outer() != self.unresolved_mark
+                    || matches!(&*ident.sym, "undefined" | "NaN" | "Infinity")
+            }
+            Expr::Class(class) => self.is_pure_class(&class.class),
+            Expr::Paren(paren) => self.is_pure_expr(&paren.expr),
+            Expr::Tpl(tpl) => tpl.exprs.iter().all(|expr| self.is_pure_expr(expr)),
+            Expr::Unary(unary) => unary.op != UnaryOp::Delete && self.is_pure_expr(&unary.arg),
+            Expr::Bin(bin) => self.is_pure_expr(&bin.left) && self.is_pure_expr(&bin.right),
+            Expr::Cond(cond) => {
+                self.is_pure_expr(&cond.test)
+                    && self.is_pure_expr(&cond.cons)
+                    && self.is_pure_expr(&cond.alt)
+            }
+            Expr::Seq(seq) => seq.exprs.iter().all(|expr| self.is_pure_expr(expr)),
+            Expr::Array(array) => array
+                .elems
+                .iter()
+                .flatten()
+                .all(|elem| elem.spread.is_none() && self.is_pure_expr(&elem.expr)),
+            Expr::Object(object) => object.props.iter().all(|prop| match prop {
+                PropOrSpread::Spread(_) => false,
+                PropOrSpread::Prop(prop) => match &**prop {
+                    Prop::Shorthand(ident) => self.is_pure_expr(&Expr::Ident(ident.clone())),
+                    Prop::KeyValue(kv) => {
+                        self.is_pure_prop_name(&kv.key) && self.is_pure_expr(&kv.value)
+                    }
+                    Prop::Method(method) => self.is_pure_prop_name(&method.key),
+                    Prop::Getter(getter) => self.is_pure_prop_name(&getter.key),
+                    Prop::Setter(setter) => self.is_pure_prop_name(&setter.key),
+                    Prop::Assign(_) => false,
+                },
+            }),
+            Expr::Call(call) => {
+                self.is_pure_annotated(call.span.lo)
+                    && call
+                        .args
+                        .iter()
+                        .all(|arg| arg.spread.is_none() && self.is_pure_expr(&arg.expr))
+            }
+            Expr::New(new) => {
+                self.is_pure_annotated(new.span.lo)
+                    && new
+                        .args
+                        .iter()
+                        .flatten()
+                        .all(|arg| arg.spread.is_none() && self.is_pure_expr(&arg.expr))
+            }
+            _ => false,
+        }
+    }
+
+    fn is_pure_annotated(&self, pos: BytePos) -> bool {
+        self.comments.with_leading(pos, |comments| {
+            comments
+                .iter()
+                .any(|comment| matches!(comment.text.trim(), "#__PURE__" | "@__PURE__"))
+        })
+    }
+
+    fn is_pure_prop_name(&self, key: &PropName) -> bool {
+        match key {
+            PropName::Computed(computed) => self.is_pure_expr(&computed.expr),
+            _ => true,
+        }
+    }
+}
+
+/// Drops unused exports and side-effect-free modules nobody uses, then removes
+/// top-level declarations left unreferenced, until nothing changes.
+fn tree_shake(module_graph: &mut ModuleGraph, context: Arc<Context>) {
+    let mut removed_modules = vec![];
+    let mut removed_exports = HashMap::<String, Vec<String>>::new();
+    loop {
+        let used = used_exports(module_graph, context.clone());
+        let excluded = module_graph
+            .modules
+            .keys()
+            .filter(|path| !used.contains_key(*path))
+            .cloned()
+            .collect::<HashSet<_>>();
+        let mut changed = !excluded.is_empty();
+        excluded.iter().for_each(|path| {
+            module_graph.modules.shift_remove(path);
+        });
+        removed_modules.extend(excluded.iter().cloned());
+        for (path, module) in module_graph.modules.iter_mut() {
+            let specifiers = module
+                .deps
+                .iter()
+                .map(|(dep, resolved)| {
+                    (
+                        dep.specifier.clone(),
+                        resolved.to_string_lossy().to_string(),
+                    )
+                })
+                .collect::<HashMap<_, _>>();
+            // later stages look every dep up in the graph
+            module
+                .deps
+                .retain(|(_, resolved)| !excluded.contains(&*resolved.to_string_lossy()));
+            if !module.bindings.is_esm {
+                continue;
+            }
+            let is_excluded = |src: &Str| {
+                specifiers
+                    .get(&*src.value)
+                    .map_or(false, |resolved| excluded.contains(resolved))
+            };
+            let removed = shake_exports(&mut module.ast, &used[path], is_excluded);
+            changed |= !removed.is_empty();
+            removed_exports
+                .entry(path.clone())
+                .or_default()
+                .extend(removed);
+            changed |= GLOBALS.set(&context.globals, || {
+                shake_declarations(&mut module.ast, &context.comments)
+            });
+            module.bindings = analyze_bindings(&module.ast);
+        }
+        if !changed {
+            break;
+        }
+    }
+    if context.config.debug {
+        removed_modules.sort();
+        removed_modules
+            .iter()
+            .for_each(|path| eprintln!("tree shaking: removed module {}", path));
+        let mut removed_exports = removed_exports
+            .into_iter()
+            .filter(|(_, exports)| !exports.is_empty())
+            .collect::<Vec<_>>();
+        removed_exports.sort();
+        removed_exports.iter().for_each(|(path, exports)| {
+            eprintln!(
+                "tree shaking: removed exports {} from {}",
+                exports.join(", "),
+                path
+            )
+        });
+    }
+}
+
+/// Used exports of every module reachable from the entries, modules missing
+/// from the result can be dropped.
+fn used_exports(module_graph: &ModuleGraph, context: Arc<Context>) -> HashMap<String, UsedExports> {
+    let mut used = context
+        .entries
+        .iter()
+        .map(|entry| (entry.to_string_lossy().to_string(), UsedExports::All))
+        .collect::<HashMap<_, _>>();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        let included = used.keys().cloned().collect::<Vec<_>>();
+        for path in included {
+            let Some(module) = module_graph.modules.get(&path) else {
+                continue;
+            };
+            let module_used = used[&path].clone();
+            let bindings = &module.bindings;
+            let mut add = |dep: &str, names: &UsedExports| {
+                let side_effects = module_graph
+                    .modules
+                    .get(dep)
+                    .map_or(true, |module| module.side_effects);
+                if names.is_empty() && !side_effects {
+                    return;
+                }
+                match used.get_mut(dep) {
+                    Some(dep_used) => changed |= dep_used.extend(names),
+                    None => {
+                        used.insert(dep.to_string(), names.clone());
+                        changed = true;
+                    }
+                }
+            };
+            module.deps.iter().for_each(|(dep, resolved)| {
+                let resolved = resolved.to_string_lossy();
+                let names = match dep.kind {
+                    _ if !bindings.is_esm => UsedExports::All,
+                    DependencyKind::Static => bindings
+                        .imports
+                        .iter()
+                        .filter(|(specifier, _)| *specifier == dep.specifier)
+                        .fold(UsedExports::empty(), |mut used, (_, names)| {
+                            used.extend(names);
+                            used
+                        }),
+                    DependencyKind::ReExport => {
+                        let mut names = UsedExports::empty();
+                        bindings
+                            .reexports
+                            .iter()
+                            .filter(|(specifier, _)| *specifier == dep.specifier)
+                            .flat_map(|(_, specifiers)| specifiers)
+                            .filter(|(_, exported)| module_used.contains(exported))
+                            .for_each(|(imported, _)| {
+                                names.extend(&match imported.as_str() {
+                                    "*" => UsedExports::All,
+                                    _ => UsedExports::Names(HashSet::from([imported.clone()])),
+                                });
+                            });
+                        if bindings.star_reexports.contains(&dep.specifier) {
+                            names.extend(&star_reexported(
+                                &module_used,
+                                bindings,
+                                &resolved,
+                                module_graph,
+                            ));
+                        }
+                        names
+                    }
+                    _ => UsedExports::All,
+                };
+                add(&resolved, &names);
+            });
+        }
+    }
+    used
+}
+
+/// Names requested through `export * from` that the target module provides
+/// and the re-exporting module does not define itself.
+fn star_reexported(
+    used: &UsedExports,
+    bindings: &ModuleBindings,
+    target: &str,
+    module_graph: &ModuleGraph,
+) -> UsedExports {
+    let provided = exported_names(target, module_graph, &mut HashSet::new());
+    match used {
+        UsedExports::All => UsedExports::All,
+        UsedExports::Names(names) => UsedExports::Names(
+            names
+                .iter()
+                .filter(|name| {
+                    *name != "default"
+                        && provided.contains(name)
+                        && !bindings.local_exports.contains(*name)
+                        && !bindings.reexports.iter().any(|(_, specifiers)| {
+                            specifiers.iter().any(|(_, exported)| exported == *name)
+                        })
+                })
+                .cloned()
+                .collect(),
+        ),
+    }
+}
+
+/// Every name a module exports, `All` when it can't be known statically.
+fn exported_names(
+    path: &str,
+    module_graph: &ModuleGraph,
+    visited: &mut HashSet<String>,
+) -> UsedExports {
+    let Some(module) = module_graph.modules.get(path) else {
+        return UsedExports::All;
+    };
+    if !module.bindings.is_esm {
+        return UsedExports::All;
+    }
+    let mut names = UsedExports::Names(
+        module
+            .bindings
+            .local_exports
+            .iter()
+            .chain(
+                module
+                    .bindings
+                    .reexports
+                    .iter()
+                    .flat_map(|(_, specifiers)| specifiers.iter().map(|(_, exported)| exported)),
+            )
+            .cloned()
+            .collect(),
+    );
+    // cyclic star re-exports add nothing new
+    if !visited.insert(path.to_string()) {
+        return names;
+    }
+    module
+        .deps
+        .iter()
+        .filter(|(dep, _)| module.bindings.star_reexports.contains(&dep.specifier))
+        .for_each(|(_, resolved)| {
+            let star = exported_names(&resolved.to_string_lossy(), module_graph, visited);
+            names.extend(&match star {
+                UsedExports::All => UsedExports::All,
+                UsedExports::Names(star) => {
+                    UsedExports::Names(star.into_iter().filter(|name| name != "default").collect())
+                }
+            });
+        });
+    names
+}
+
+/// Turns unused exports into plain declarations and removes imports and
+/// re-exports of dropped modules, returning the removed export names.
+fn shake_exports(
+    ast: &mut Ast,
+    used: &UsedExports,
+    is_excluded: impl Fn(&Str) -> bool,
+) -> Vec<String> {
+    let mut removed = vec![];
+    let body = ast.ast.body.take();
+    ast.ast.body = body
+        .into_iter()
+        .filter_map(|item| {
+            let ModuleItem::ModuleDecl(decl) = item else {
+                return Some(item);
+            };
+            let decl = match decl {
+                ModuleDecl::Import(import) if is_excluded(&import.src) => return None,
+                ModuleDecl::ExportAll(export) if is_excluded(&export.src) => return None,
+                ModuleDecl::ExportNamed(NamedExport {
+                    src: Some(ref src), ..
+                }) if is_excluded(src) => return None,
+                ModuleDecl::ExportDecl(export) => {
+                    let names = decl_names(&export.decl);
+                    if names.iter().any(|name| used.contains(name)) {
+                        ModuleDecl::ExportDecl(export)
+                    } else {
+                        removed.extend(names);
+                        return Some(ModuleItem::Stmt(Stmt::Decl(export.decl)));
+                    }
+                }
+                ModuleDecl::ExportDefaultDecl(export) if !used.contains("default") => {
+                    removed.push("default".to_string());
+                    return match export.decl {
+                        DefaultDecl::Fn(FnExpr {
+                            ident: Some(ident),
+                            function,
+                        }) => Some(ModuleItem::Stmt(Stmt::Decl(Decl::Fn(FnDecl {
+                            ident,
+                            declare: false,
+                            function,
+                        })))),
+                        DefaultDecl::Class(ClassExpr {
+                            ident: Some(ident),
+                            class,
+                        }) => Some(ModuleItem::Stmt(Stmt::Decl(Decl::Class(ClassDecl {
+                            ident,
+                            declare: false,
+                            class,
+                        })))),
+                        DefaultDecl::Class(class) => Some(ModuleItem::Stmt(Stmt::Expr(ExprStmt {
+                            span: export.span,
+                            expr: Box::new(Expr::Class(class)),
+                        }))),
+                        _ => None,
+                    };
+                }
+                ModuleDecl::ExportDefaultExpr(export) if !used.contains("default") => {
+                    removed.push("default".to_string());
+                    return Some(ModuleItem::Stmt(Stmt::Expr(ExprStmt {
+                        span: export.span,
+                        expr: export.expr,
+                    })));
+                }
+                ModuleDecl::ExportNamed(mut export) => {
+                    export.specifiers.retain(|specifier| {
+                        let exported = match specifier {
+                            ExportSpecifier::Named(named) => {
+                                export_name(named.exported.as_ref().unwrap_or(&named.orig))
+                            }
+                            ExportSpecifier::Default(default) => default.exported.sym.to_string(),
+                            ExportSpecifier::Namespace(namespace) => export_name(&namespace.name),
+                        };
+                        let is_used = used.contains(&exported);
+                        if !is_used {
+                            removed.push(exported);
+                        }
+                        is_used
+                    });
+                    match export.src {
+                        _ if !export.specifiers.is_empty() => ModuleDecl::ExportNamed(export),
+                        // the re-exported module may still have side effects
+                        Some(src) => ModuleDecl::Import(ImportDecl {
+                            span: export.span,
+                            specifiers: vec![],
+                            src,
+                            type_only: false,
+                            with: export.with,
+                        }),
+                        None => return None,
+                    }
+                }
+                decl => decl,
+            };
+            Some(ModuleItem::ModuleDecl(decl))
+        })
+        .collect();
+    removed
+}
+
+/// Removes unreferenced pure top-level declarations and unused import
+/// specifiers, returning whether anything was removed.
+fn shake_declarations(ast: &mut Ast, comments: &SwcComments) -> bool {
+    let purity = Purity {
+        comments,
+        unresolved_mark: ast.unresolved_mark,
+    };
+    let mut changed = false;
+    loop {
+        let references = ast
+            .ast
+            .body
+            .iter()
+            .map(|item| {
+                let mut collector = IdentCollector::default();
+                item.visit_with(&mut collector);
+                collector.ids
+            })
+            .collect::<Vec<_>>();
+        // references from other items, a declaration referencing itself stays removable
+        let is_referenced = |id: &Id, index: usize| {
+            references
+                .iter()
+                .enumerate()
+                .any(|(i, ids)| i != index && ids.contains(id))
+        };
+        let mut removed = false;
+        let body = ast.ast.body.take();
+        ast.ast.body = body
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, mut item)| {
+                match &mut item {
+                    ModuleItem::ModuleDecl(ModuleDecl::Import(import)) => {
+                        let len = import.specifiers.len();
+                        import.specifiers.retain(|specifier| {
+                            let local = match specifier {
+                                ImportSpecifier::Named(named) => &named.local,
+                                ImportSpecifier::Default(default) => &default.local,
+                                ImportSpecifier::Namespace(namespace) => &namespace.local,
+                            };
+                            is_referenced(&local.to_id(), index)
+                        });
+                        removed |= import.specifiers.len() != len;
+                    }
+                    ModuleItem::Stmt(Stmt::Decl(Decl::Var(var))) => {
+                        let len = var.decls.len();
+                        var.decls.retain(|decl| {
+                            !purity.is_pure_var_declarator(decl)
+                                || find_pat_ids::<_, Id>(&decl.name)
+                                    .iter()
+                                    .any(|id| is_referenced(id, index))
+                        });
+                        removed |= var.decls.len() != len;
+                        if var.decls.is_empty() {
+                            return None;
+                        }
+                    }
+                    ModuleItem::Stmt(Stmt::Decl(Decl::Fn(function)))
+                        if !is_referenced(&function.ident.to_id(), index) =>
+                    {
+                        removed = true;
+                        return None;
+                    }
+                    ModuleItem::Stmt(Stmt::Decl(Decl::Class(class)))
+                        if !is_referenced(&class.ident.to_id(), index)
+                            && purity.is_pure_class(&class.class) =>
+                    {
+                        removed = true;
+                        return None;
+                    }
+                    // keep directives like "use strict"
+                    ModuleItem::Stmt(Stmt::Expr(ExprStmt { expr, .. }))
+                        if !matches!(&**expr, Expr::Lit(Lit::Str(_)))
+                            && purity.is_pure_expr(expr) =>
+                    {
+                        removed = true;
+                        return None;
+                    }
+                    _ => {}
+                }
+                Some(item)
+            })
+            .collect();
+        if !removed {
+            return changed;
+        }
+        changed = true;
+    }
+}
+
+#[derive(Default)]
+struct IdentCollector {
+    ids: HashSet<Id>,
+}
+
+impl Visit for IdentCollector {
+    fn visit_ident(&mut self, ident: &Ident) {
+        self.ids.insert(ident.to_id());
+    }
+}
+
+/////////////////////////////////////////
+// Scope Hoisting
+
+/// Modules that can share a single scope: strict ESM modules outside of
+/// cycles, only imported statically by other such modules.
+fn hoistable_modules(module_graph: &ModuleGraph, context: Arc<Context>) -> HashSet<String> {
+    let mut hoistable = GLOBALS.set(&context.globals, || {
+        module_graph
+            .modules
+            .iter()
+            .filter(|(_, module)| is_hoistable(module))
+            .map(|(path, _)| path.clone())
+            .collect::<HashSet<_>>()
+    });
+    cyclic_modules(module_graph).iter().for_each(|path| {
+        hoistable.remove(path);
+    });
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (path, module) in &module_graph.modules {
+            let is_hoisted = hoistable.contains(path);
+            for (dep, resolved) in &module.deps {
+                let resolved = resolved.to_string_lossy();
+                let is_static =
+                    matches!(dep.kind, DependencyKind::Static | DependencyKind::ReExport);
+                if is_hoisted && !hoistable.contains(&*resolved) {
+                    // a hoisted module can't import from a wrapped one
+                    hoistable.remove(path);
+                    changed = true;
+                    break;
+                }
+                if (!is_hoisted || !is_static) && hoistable.remove(&*resolved) {
+                    changed = true;
+                }
+            }
+        }
+    }
+    hoistable
+}
+
+fn is_hoistable(module: &Module) -> bool {
+    if !module.bindings.is_esm
+        || module
+            .deps
+            .iter()
+            .any(|(dep, _)| !matches!(dep.kind, DependencyKind::Static | DependencyKind::ReExport))
+    {
+        return false;
+    }
+    let specifiers = module
+        .deps
+        .iter()
+        .map(|(dep, _)| dep.specifier.as_str())
+        .collect::<HashSet<_>>();
+    // externals and namespace objects are left to the runtime
+    let links_statically = module.ast.ast.body.iter().all(|item| match item {
+        ModuleItem::ModuleDecl(ModuleDecl::Import(import)) => {
+            specifiers.contains(&*import.src.value)
+                && !import
+                    .specifiers
+                    .iter()
+                    .any(|specifier| matches!(specifier, ImportSpecifier::Namespace(_)))
+        }
+        ModuleItem::ModuleDecl(ModuleDecl::ExportAll(export)) => {
+            specifiers.contains(&*export.src.value)
+        }
+        ModuleItem::ModuleDecl(ModuleDecl::ExportNamed(NamedExport {
+            src: Some(src),
+            specifiers: export_specifiers,
+            ..
+        })) => {
+            specifiers.contains(&*src.value)
+                && !export_specifiers
+                    .iter()
+                    .any(|specifier| matches!(specifier, ExportSpecifier::Namespace(_)))
+        }
+        ModuleItem::ModuleDecl(
+            ModuleDecl::TsImportEquals(_) | ModuleDecl::TsExportAssignment(_),
+        ) => false,
+        _ => true,
+    });
+    let mut visitor = CommonJsDetector {
+        unresolved_mark: module.ast.unresolved_mark,
+        found: false,
+    };
+    module.ast.ast.visit_with(&mut visitor);
+    links_statically && !visitor.found
+}
+
+/// Finds uses of `module`, `exports`, `require` or `import.meta`.
+struct CommonJsDetector {
+    unresolved_mark: Mark,
+    found: bool,
+}
+
+impl Visit for CommonJsDetector {
+    fn visit_ident(&mut self, ident: &Ident) {
+        if ident.span.ctxt.outer() == self.unresolved_mark
+            && matches!(&*ident.sym, "module" | "exports" | "require")
+        {
+            self.found = true;
+        }
+    }
+
+    fn visit_meta_prop_expr(&mut self, _: &MetaPropExpr) {
+        self.found = true;
+    }
+}
+
+fn cyclic_modules(module_graph: &ModuleGraph) -> HashSet<String> {
+    // a module is in a cycle when it can reach itself
+    module_graph
+        .modules
+        .keys()
+        .filter(|path| {
+            let mut visited = HashSet::new();
+            let mut stack = vec![path.to_string()];
+            while let Some(current) = stack.pop() {
+                let Some(module) = module_graph.modules.get(&current) else {
+                    continue;
+                };
+                for (_, resolved) in &module.deps {
+                    let resolved = resolved.to_string_lossy().to_string();
+                    if resolved == **path {
+                        return true;
+                    }
+                    if visited.insert(resolved.clone()) {
+                        stack.push(resolved);
+                    }
+                }
+            }
+            false
+        })
+        .cloned()
+        .collect()
+}
+
+/// Where an export name of a hoisted module points to.
+enum ExportTarget {
+    Local(Id),
+    ReExport(String, String),
+}
+
+#[derive(Default)]
+struct ModuleLinks {
+    exports: HashMap<String, ExportTarget>,
+    star_reexports: Vec<String>,
+    /// Local import bindings to the module and export name they import
+    imports: HashMap<Id, (String, String)>,
+}
+
+/// Concatenates the hoisted modules in evaluation order into a single module,
+/// linking imports directly to the exported bindings. Colliding top-level
+/// names differ by their `top_level_mark` and are renamed by `hygiene`.
+fn concatenate(
+    module_graph: &mut ModuleGraph,
+    hoisted: &HashSet<String>,
+    context: Arc<Context>,
+) -> Option<Ast> {
+    let order = hoisted_order(module_graph, hoisted, context.clone());
+    let first = module_graph.modules.get(order.first()?)?;
+    let unresolved_mark = first.ast.unresolved_mark;
+    let top_level_mark = first.ast.top_level_mark;
+    GLOBALS.set(&context.globals, || {
+        let links = order
+            .iter()
+            .map(|path| {
+                let module = module_graph.modules.get_mut(path).unwrap();
+                (path.clone(), strip_module_decls(module))
+            })
+            .collect::<HashMap<_, _>>();
+        let unresolved_ctxt = SyntaxContext::empty().apply_mark(unresolved_mark);
+        let mut body = vec![];
+        for path in &order {
+            let module = module_graph.modules.get_mut(path).unwrap();
+            let bindings = links[path]
+                .imports
+                .iter()
+                .map(|(local, (source, name))| {
+                    let target = resolve_export(&links, source, name, &mut HashSet::new());
+                    (local.clone(), target)
+                })
+                .collect();
+            module.ast.ast.visit_mut_with(&mut ImportLinker {
+                bindings,
+                unresolved_mark: module.ast.unresolved_mark,
+                unresolved_ctxt,
+            });
+            body.append(&mut module.ast.ast.body);
+        }
+        let mut ast = Ast {
+            ast: SwcModule {
+                span: DUMMY_SP,
+                body,
+                shebang: None,
+            },
+            unresolved_mark,
+            top_level_mark,
+        };
+        ast.ast.visit_mut_with(&mut hygiene());
+        ast.ast.visit_mut_with(&mut fixer(Some(&context.comments)));
+        Some(ast)
+    })
+}
+
+/// Hoisted modules ordered so that dependencies come before their importers.
+fn hoisted_order(
+    module_graph: &ModuleGraph,
+    hoisted: &HashSet<String>,
+    context: Arc<Context>,
+) -> Vec<String> {
+    fn visit(
+        path: &str,
+        module_graph: &ModuleGraph,
+        visited: &mut HashSet<String>,
+        order: &mut Vec<String>,
+    ) {
+        if !visited.insert(path.to_string()) {
+            return;
+        }
+        if let Some(module) = module_graph.modules.get(path) {
+            module.deps.iter().for_each(|(_, resolved)| {
+                visit(&resolved.to_string_lossy(), module_graph, visited, order)
+            });
+        }
+        order.push(path.to_string());
+    }
+    let mut visited = HashSet::new();
+    let mut order = vec![];
+    context
+        .entries
+        .iter()
+        .map(|entry| entry.to_string_lossy().to_string())
+        .filter(|entry| hoisted.contains(entry))
+        .for_each(|entry| visit(&entry, module_graph, &mut visited, &mut order));
+    order
+}
+
+/// Removes imports and exports from a hoisted module, recording what they linked.
+fn strip_module_decls(module: &mut Module) -> ModuleLinks {
+    let sources = module
+        .deps
+        .iter()
+        .map(|(dep, resolved)| {
+            (
+                dep.specifier.clone(),
+                resolved.to_string_lossy().to_string(),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+    // a fresh mark, so hygiene renames it apart from a `_default` of the module
+    let default_ident = || private_ident!("_default");
+    let mut links = ModuleLinks::default();
+    let body = module.ast.ast.body.take();
+    module.ast.ast.body = body
+        .into_iter()
+        .filter_map(|item| {
+            let ModuleItem::ModuleDecl(decl) = item else {
+                return Some(item);
+            };
+            let stmt = match decl {
+                ModuleDecl::Import(import) => {
+                    let source = &sources[&*import.src.value];
+                    import.specifiers.iter().for_each(|specifier| {
+                        let (local, name) = match specifier {
+                            ImportSpecifier::Named(named) => (
+                                &named.local,
+                                named
+                                    .imported
+                                    .as_ref()
+                                    .map(export_name)
+                                    .unwrap_or_else(|| named.local.sym.to_string()),
+                            ),
+                            ImportSpecifier::Default(default) => {
+                                (&default.local, "default".to_string())
+                            }
+                            // namespace imports are never hoisted
+                            ImportSpecifier::Namespace(namespace) => {
+                                (&namespace.local, "*".to_string())
+                            }
+                        };
+                        links.imports.insert(local.to_id(), (source.clone(), name));
+                    });
+                    return None;
+                }
+                ModuleDecl::ExportDecl(export) => {
+                    let ids: Vec<Id> = match &export.decl {
+                        Decl::Class(class) => vec![class.ident.to_id()],
+                        Decl::Fn(function) => vec![function.ident.to_id()],
+                        decl => find_pat_ids(decl),
+                    };
+                    ids.into_iter().for_each(|id| {
+                        links
+                            .exports
+                            .insert(id.0.to_string(), ExportTarget::Local(id));
+                    });
+                    Stmt::Decl(export.decl)
+                }
+                ModuleDecl::ExportDefaultDecl(export) => {
+                    let decl = match export.decl {
+                        DefaultDecl::Fn(function) => Decl::Fn(FnDecl {
+                            ident: function.ident.unwrap_or_else(default_ident),
+                            declare: false,
+                            function: function.function,
+                        }),
+                        DefaultDecl::Class(class) => Decl::Class(ClassDecl {
+                            ident: class.ident.unwrap_or_else(default_ident),
+                            declare: false,
+                            class: class.class,
+                        }),
+                        DefaultDecl::TsInterfaceDecl(_) => return None,
+                    };
+                    let ident = match &decl {
+                        Decl::Fn(function) => function.ident.to_id(),
+                        Decl::Class(class) => class.ident.to_id(),
+                        _ => unreachable!(),
+                    };
+                    links
+                        .exports
+                        .insert("default".to_string(), ExportTarget::Local(ident));
+                    Stmt::Decl(decl)
+                }
+                ModuleDecl::ExportDefaultExpr(export) => {
+                    let ident = default_ident();
+                    links
+                        .exports
+                        .insert("default".to_string(), ExportTarget::Local(ident.to_id()));
+                    // preset_env already ran
+                    Stmt::Decl(Decl::Var(Box::new(VarDecl {
+                        span: export.span,
+                        kind: VarDeclKind::Var,
+                        declare: false,
+                        decls: vec![VarDeclarator {
+                            span: export.span,
+                            name: Pat::Ident(ident.into()),
+                            init: Some(export.expr),
+                            definite: false,
+                        }],
+                    })))
+                }
+                ModuleDecl::ExportNamed(export) => {
+                    let source = export.src.as_ref().map(|src| &sources[&*src.value]);
+                    export.specifiers.iter().for_each(|specifier| {
+                        let (orig, exported) = match specifier {
+                            ExportSpecifier::Named(named) => {
+                                (&named.orig, named.exported.as_ref().unwrap_or(&named.orig))
+                            }
+                            // namespace and default re-exports are never hoisted
+                            _ => return,
+                        };
+                        let target = match (source, orig) {
+                            (Some(source), orig) => {
+                                ExportTarget::ReExport(source.clone(), export_name(orig))
+                            }
+                            (None, ModuleExportName::Ident(ident)) => {
+                                ExportTarget::Local(ident.to_id())
+                            }
+                            (None, ModuleExportName::Str(_)) => return,
+                        };
+                        links.exports.insert(export_name(exported), target);
+                    });
+                    return None;
+                }
+                ModuleDecl::ExportAll(export) => {
+                    links
+                        .star_reexports
+                        .push(sources[&*export.src.value].clone());
+                    return None;
+                }
+                _ => return None,
+            };
+            Some(ModuleItem::Stmt(stmt))
+        })
+        .collect();
+    links
+}
+
+/// The binding behind an export, following re-exports and imported locals.
+fn resolve_export(
+    links: &HashMap<String, ModuleLinks>,
+    path: &str,
+    name: &str,
+    visited: &mut HashSet<(String, String)>,
+) -> Option<Id> {
+    if !visited.insert((path.to_string(), name.to_string())) {
+        return None;
+    }
+    let module = links.get(path)?;
+    match module.exports.get(name) {
+        Some(ExportTarget::Local(id)) => match module.imports.get(id) {
+            Some((source, name)) => resolve_export(links, source, name, visited),
+            None => Some(id.clone()),
+        },
+        Some(ExportTarget::ReExport(source, name)) => resolve_export(links, source, name, visited),
+        None if name != "default" => module
+            .star_reexports
+            .iter()
+            .find_map(|source| resolve_export(links, source, name, visited)),
+        None => None,
+    }
+}
+
+/// Points import bindings at the bindings they import and moves unresolved
+/// references into the shared unresolved context.
+struct ImportLinker {
+    /// Missing exports evaluate to `undefined`
+    bindings: HashMap<Id, Option<Id>>,
+    unresolved_mark: Mark,
+    unresolved_ctxt: SyntaxContext,
+}
+
+impl ImportLinker {
+    fn linked(&self, ident: &Ident) -> Option<Ident> {
+        let target = self.bindings.get(&ident.to_id())?;
+        let (sym, ctxt) = target
+            .clone()
+            .unwrap_or_else(|| ("undefined".into(), self.unresolved_ctxt));
+        Some(Ident::new(sym, ident.span.with_ctxt(ctxt)))
+    }
+}
+
+impl VisitMut for ImportLinker {
+    fn visit_mut_ident(&mut self, ident: &mut Ident) {
+        if let Some(linked) = self.linked(ident) {
+            *ident = linked;
+        } else if ident.span.ctxt.outer() == self.unresolved_mark {
+            ident.span.ctxt = self.unresolved_ctxt;
+        }
+    }
+
+    fn visit_mut_prop(&mut self, prop: &mut Prop) {
+        if let Prop::Shorthand(ident) = prop {
+            if let Some(linked) = self.linked(ident) {
+                *prop = Prop::KeyValue(KeyValueProp {
+                    key: PropName::Ident(Ident::new(ident.sym.clone(), ident.span)),
+                    value: Box::new(Expr::Ident(linked)),
+                });
+                return;
+            }
+        }
+        prop.visit_mut_children_with(self);
+    }
+
+    fn visit_mut_member_prop(&mut self, prop: &mut MemberProp) {
+        // `a.b` does not reference `b`
+        if let MemberProp::Computed(computed) = prop {
+            computed.visit_mut_with(self);
+        }
+    }
+
+    fn visit_mut_prop_name(&mut self, name: &mut PropName) {
+        if let PropName::Computed(computed) = name {
+            computed.visit_mut_with(self);
+        }
+    }
+}
+
+/////////////////////////////////////////
+// Code Splitting
+
+struct ChunkGraph {
+    /// The entry chunk comes first
+    chunks: Vec<Chunk>,
+    /// Chunks to load, in order, before a dynamically imported module can be required
+    async_imports: HashMap<String, Vec<String>>,
+}
+
+struct Chunk {
+    id: String,
+    kind: ChunkKind,
+    /// Modules in the order they were reached
+    modules: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChunkKind {
+    /// Loaded by a script tag, holds the runtime
+    Entry,
+    /// Starts at a dynamically imported module
+    Async,
+    /// Modules shared by several async chunks
+    Common,
+    /// Started by `new Worker(new URL('x', import.meta.url))`, holds its own
+    /// runtime and everything the worker imports
+    Worker,
+}
+
+impl Chunk {
+    fn filename(&self, context: Arc<Context>) -> String {
+        context.config.output.filename.replace("[name]", &self.id)
+    }
+}
+
+/// Splits the graph at dynamic imports: the entries and everything they import
+/// statically form the entry chunk, each dynamically imported module starts an
+/// async chunk, and modules found in several async chunks move to common chunks.
+/// Workers get chunks of their own.
+fn build_chunk_graph(module_graph: &ModuleGraph, context: Arc<Context>) -> ChunkGraph {
+    let entries = context
+        .entries
+        .iter()
+        .map(|entry| entry.to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    let mut async_roots = vec![];
+    let mut worker_roots = vec![];
+    let entry_modules = collect_chunk_modules(
+        module_graph,
+        &entries,
+        &HashSet::new(),
+        &mut async_roots,
+        &mut worker_roots,
+    );
+    let in_entry = entry_modules.iter().cloned().collect::<HashSet<_>>();
+    let mut chunks = vec![Chunk {
+        id: "bundle".to_string(),
+        kind: ChunkKind::Entry,
+        modules: entry_modules,
+    }];
+    // dynamic imports of modules already in the entry chunk need no chunk
+    let mut visited = HashSet::new();
+    let mut index = 0;
+    while index < async_roots.len() {
+        let root = async_roots[index].clone();
+        index += 1;
+        if in_entry.contains(&root) || !visited.insert(root.clone()) {
+            continue;
+        }
+        let modules = collect_chunk_modules(
+            module_graph,
+            std::slice::from_ref(&root),
+            &in_entry,
+            &mut async_roots,
+            &mut worker_roots,
+        );
+        chunks.push(Chunk {
+            id: chunk_id(&root, context.clone()),
+            kind: ChunkKind::Async,
+            modules,
+        });
+    }
+
+    // modules keyed by the async chunks containing them
+    let mut owners = HashMap::<String, Vec<usize>>::new();
+    chunks
+        .iter()
+        .enumerate()
+        .skip(1)
+        .for_each(|(index, chunk)| {
+            chunk.modules.iter().for_each(|module| {
+                owners.entry(module.clone()).or_default().push(index);
+            });
+        });
+    let mut common_chunks: Vec<(Vec<usize>, Vec<String>)> = vec![];
+    chunks.iter().skip(1).for_each(|chunk| {
+        chunk.modules.iter().for_each(|module| {
+            let owners = &owners[module];
+            if owners.len() < 2 {
+                return;
+            }
+            match common_chunks
+                .iter_mut()
+                .find(|(chunks, _)| chunks == owners)
+            {
+                Some((_, modules)) => {
+                    if !modules.contains(module) {
+                        modules.push(module.clone())
+                    }
+                }
+                None => common_chunks.push((owners.clone(), vec![module.clone()])),
+            }
+        });
+    });
+    let shared = owners
+        .iter()
+        .filter(|(_, owners)| owners.len() > 1)
+        .map(|(module, _)| module.clone())
+        .collect::<HashSet<_>>();
+    let mut async_imports = HashMap::new();
+    chunks
+        .iter()
+        .skip(1)
+        .enumerate()
+        .for_each(|(index, chunk)| {
+            let index = index + 1;
+            let mut ids = common_chunks
+                .iter()
+                .filter(|(owners, _)| owners.contains(&index))
+                .map(|(owners, _)| common_chunk_id(owners, &chunks))
+                .collect::<Vec<_>>();
+            // a chunk left empty by the extraction is not loaded
+            if chunk.modules.iter().any(|module| !shared.contains(module)) {
+                ids.push(chunk.id.clone());
+            }
+            async_imports.insert(chunk.modules[0].clone(), ids);
+        });
+    let common_chunks = common_chunks
+        .into_iter()
+        .map(|(owners, modules)| Chunk {
+            id: common_chunk_id(&owners, &chunks),
+            kind: ChunkKind::Common,
+            modules,
+        })
+        .collect::<Vec<_>>();
+    chunks.iter_mut().skip(1).for_each(|chunk| {
+        chunk.modules.retain(|module| !shared.contains(module));
+    });
+    chunks.retain(|chunk| chunk.kind == ChunkKind::Entry || !chunk.modules.is_empty());
+    chunks.extend(common_chunks);
+    // a worker can't load chunks of the page, it gets its dynamic imports too
+    let mut workers = HashSet::new();
+    let mut index = 0;
+    while index < worker_roots.len() {
+        let root = worker_roots[index].clone();
+        index += 1;
+        if !workers.insert(root.clone()) {
+            continue;
+        }
+        let mut modules = vec![];
+        let mut roots = vec![root.clone()];
+        while !roots.is_empty() {
+            let collected = modules.iter().cloned().collect::<HashSet<_>>();
+            let mut dynamic = vec![];
+            modules.extend(collect_chunk_modules(
+                module_graph,
+                &roots,
+                &collected,
+                &mut dynamic,
+                &mut worker_roots,
+            ));
+            let mut seen = HashSet::new();
+            dynamic.retain(|root| !modules.contains(root) && seen.insert(root.clone()));
+            roots = dynamic;
+        }
+        chunks.push(Chunk {
+            id: chunk_id(&root, context.clone()),
+            kind: ChunkKind::Worker,
+            modules,
+        });
+    }
+    ChunkGraph {
+        chunks,
+        async_imports,
+    }
+}
+
+/// Modules reachable from `roots` without crossing dynamic imports or
+/// workers, whose targets are pushed to `async_roots` and `worker_roots`.
+fn collect_chunk_modules(
+    module_graph: &ModuleGraph,
+    roots: &[String],
+    exclude: &HashSet<String>,
+    async_roots: &mut Vec<String>,
+    worker_roots: &mut Vec<String>,
+) -> Vec<String> {
+    let mut modules = vec![];
+    let mut visited = roots.iter().cloned().collect::<HashSet<_>>();
+    let mut queue = roots
+        .iter()
+        .cloned()
+        .collect::<std::collections::VecDeque<_>>();
+    while let Some(path) = queue.pop_front() {
+        let Some(module) = module_graph.modules.get(&path) else {
+            continue;
+        };
+        module.deps.iter().for_each(|(dep, resolved)| {
+            let resolved = resolved.to_string_lossy().to_string();
+            if dep.kind == DependencyKind::Dynamic {
+                async_roots.push(resolved);
+            } else if dep.kind == DependencyKind::Worker {
+                worker_roots.push(resolved);
+            } else if !exclude.contains(&resolved) && visited.insert(resolved.clone()) {
+                queue.push_back(resolved);
+            }
+        });
+        modules.push(path);
+    }
+    modules
+}
+
+/// The root-relative path of the chunk's first module, e.g. `pages_home_ts`.
+fn chunk_id(path: &str, context: Arc<Context>) -> String {
+    let path = Path::new(path);
+    path.strip_prefix(&context.root)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>()
+        .trim_start_matches('_')
+        .to_string()
+}
+
+fn common_chunk_id(owners: &[usize], chunks: &[Chunk]) -> String {
+    std::iter::once("common")
+        .chain(owners.iter().map(|index| chunks[*index].id.as_str()))
+        .collect::<Vec<_>>()
+        .join("~")
+}
+
+/// Makes `import('x')` of a split module load its chunks first.
+fn load_async_chunks(
+    ast: &mut Ast,
+    deps: &[(Dependency, PathBuf)],
+    chunk_graph: &ChunkGraph,
+    context: Arc<Context>,
+) {
+    let chunks = deps
+        .iter()
+        .filter(|(dep, _)| dep.kind == DependencyKind::Dynamic)
+        .filter_map(|(dep, resolved)| {
+            let ids = chunk_graph
+                .async_imports
+                .get(&*resolved.to_string_lossy())?;
+            Some((dep.span, ids.clone()))
+        })
+        .collect::<HashMap<_, _>>();
+    if chunks.is_empty() {
+        return;
+    }
+    GLOBALS.set(&context.globals, || {
+        ast.ast.visit_mut_with(&mut AsyncChunkLoader {
+            chunks,
+            unresolved_ctxt: SyntaxContext::empty().apply_mark(ast.unresolved_mark),
+        });
+    });
+}
+
+/// Rewrites `import('x')` to
+/// `Promise.all([require.ensureChunk('id')]).then(function () { return import('x'); })`.
+struct AsyncChunkLoader {
+    chunks: HashMap<Span, Vec<String>>,
+    unresolved_ctxt: SyntaxContext,
+}
+
+impl VisitMut for AsyncChunkLoader {
+    fn visit_mut_expr(&mut self, expr: &mut Expr) {
+        expr.visit_mut_children_with(self);
+        let Expr::Call(call) = expr else {
+            return;
+        };
+        if !matches!(call.callee, Callee::Import(_)) {
+            return;
+        }
+        let Some(ids) = first_str_arg(Some(&call.args)).and_then(|str| self.chunks.get(&str.span))
+        else {
+            return;
+        };
+        let ident = |sym: &str| Ident::new(sym.into(), DUMMY_SP.with_ctxt(self.unresolved_ctxt));
+        let call_member = |obj: Ident, prop: &str, args: Vec<Expr>| {
+            Expr::Call(CallExpr {
+                span: DUMMY_SP,
+                callee: Callee::Expr(Box::new(Expr::Member(MemberExpr {
+                    span: DUMMY_SP,
+                    obj: Box::new(Expr::Ident(obj)),
+                    prop: MemberProp::Ident(Ident::new(prop.into(), DUMMY_SP)),
+                }))),
+                args: args.into_iter().map(|arg| arg.into()).collect(),
+                type_args: None,
+            })
+        };
+        let ensure = ids
+            .iter()
+            .map(|id| {
+                Some(call_member(ident("require"), "ensureChunk", vec![id.as_str().into()]).into())
+            })
+            .collect();
+        let loaded = call_member(
+            ident("Promise"),
+            "all",
+            vec![Expr::Array(ArrayLit {
+                span: DUMMY_SP,
+                elems: ensure,
+            })],
+        );
+        let import = Function {
+            params: vec![],
+            decorators: vec![],
+            span: DUMMY_SP,
+            body: Some(BlockStmt {
+                span: DUMMY_SP,
+                stmts: vec![Stmt::Return(ReturnStmt {
+                    span: DUMMY_SP,
+                    arg: Some(Box::new(expr.take())),
+                })],
+            }),
+            is_generator: false,
+            is_async: false,
+            type_params: None,
+            return_type: None,
+        };
+        *expr = Expr::Call(CallExpr {
+            span: DUMMY_SP,
+            callee: Callee::Expr(Box::new(Expr::Member(MemberExpr {
+                span: DUMMY_SP,
+                obj: Box::new(loaded),
+                prop: MemberProp::Ident(Ident::new("then".into(), DUMMY_SP)),
+            }))),
+            args: vec![Expr::Fn(FnExpr {
+                ident: None,
+                function: Box::new(import),
+            })
+            .into()],
+            type_args: None,
+        });
+    }
+}
+
+/////////////////////////////////////////
+// Generate Stage
+
+fn generate(
+    module_graph: &mut ModuleGraph,
+    context: Arc<Context>,
+) -> Result<Output, GenerateError> {
+    // TODO:
+    // - skip modules
+    // - ...
+
+    // before tree shaking, which drops assets only referenced by stylesheets
+    let mut files = module_graph
+        .modules
+        .values()
+        .filter_map(|module| module.asset.as_ref())
+        .map(|asset| OutputFile {
+            filename: asset.filename.clone(),
+            content: asset.content.to_vec(),
+        })
+        .collect::<Vec<_>>();
+    let mut urls = module_graph
+        .modules
+        .iter()
+        .filter_map(|(path, module)| {
+            Some((path.clone(), asset_module_url(path, module, &context)?))
+        })
+        .collect::<HashMap<_, _>>();
+    if context.config.tree_shaking() {
+        tree_shake(module_graph, context.clone());
+    }
+    let chunk_graph = build_chunk_graph(module_graph, context.clone());
+    urls.extend(
+        chunk_graph
+            .chunks
+            .iter()
+            .filter(|chunk| chunk.kind == ChunkKind::Worker)
+            .map(|chunk| {
+                let url = output_url(&chunk.filename(context.clone()), &context);
+                (chunk.modules[0].clone(), url)
+            }),
+    );
+    files.extend(extract_css(
+        module_graph,
+        &chunk_graph.chunks[0].filename(context.clone()),
+        context.clone(),
+    ));
+    let module_ids = module_ids(module_graph, context.clone());
+
+    // imported by the entries, see `transform`
+    let refresh_runtime = module_graph
+        .modules
+        .values()
+        .flat_map(|module| &module.deps)
+        .find(|(dep, _)| context.react_refresh() && dep.specifier == REACT_REFRESH_RUNTIME)
+        .map(|(_, path)| module_ids[&*path.to_string_lossy()].clone());
+    let mut runtime = Runtime {
+        modules: HashMap::new(),
+        source_maps: HashMap::new(),
+        hoisted: None,
+        module_ids,
+        refresh_runtime,
+        externals: module_graph
+            .modules
+            .values()
+            .flat_map(|module| module.externals.clone())
+            .collect(),
+    };
+    // hot updates replace single modules
+    let hoisted = if context.config.scope_hoisting() && !context.hmr {
+        hoistable_modules(module_graph, context.clone())
+    } else {
+        HashSet::new()
+    };
+    if let Some(ast) = concatenate(module_graph, &hoisted, context.clone()) {
+        let (code, mappings) =
+            ast_to_code(&ast, context.clone()).map_err(|error| GenerateError::Codegen {
+                path: "<hoisted modules>".to_string(),
+                error,
+            })?;
+        let source_map = context.config.sourcemap.map(|_| {
+            // cached modules and compiled files map back through their input maps
+            let inputs = hoisted
+                .iter()
+                .filter_map(|path| {
+                    let map = module_graph.modules[path].input_source_map.as_ref()?;
+                    Some((path.clone(), sourcemap::SourceMap::from_slice(map).ok()?))
+                })
+                .collect::<HashMap<_, _>>();
+            let map = context
+                .cm
+                .build_source_map_with_config(&mappings, None, SourceMapConfig);
+            chain_source_maps(&map, &inputs)
+        });
+        runtime.hoisted = Some((code, source_map));
+    }
+    let start = Instant::now();
+    let generated = module_graph
+        .modules
+        .par_iter_mut()
+        .filter(|(path, _)| !hoisted.contains(*path))
+        .map(|(path, module)| {
+            load_async_chunks(&mut module.ast, &module.deps, &chunk_graph, context.clone());
+            replace_deps(&mut module.ast, &module.deps, &runtime.module_ids, &urls);
+            tramsform_again(&mut module.ast, context.clone());
+            let code =
+                ast_to_code(&module.ast, context.clone()).map_err(|error| GenerateError::Codegen {
+                    path: path.clone(),
+                    error,
+                });
+            (path.clone(), code)
+        })
+        .collect::<Vec<_>>();
+    // source maps can't be sent across threads, they are built here
+    for (path, code) in generated {
+        let (code, mappings) = code?;
+        if context.config.sourcemap.is_some() {
+            let input_source_map = module_graph.modules[&path]
+                .input_source_map
+                .as_ref()
+                .and_then(|map| sourcemap::SourceMap::from_slice(map).ok());
+            let source_map = context.cm.build_source_map_with_config(
+                &mappings,
+                input_source_map.as_ref(),
+                SourceMapConfig,
+            );
+            runtime.source_maps.insert(path.clone(), source_map);
+        }
+        runtime.modules.insert(path, code);
+    }
+    if context.config.debug {
+        eprintln!(
+            "generate: {} modules in {:.2}ms on {} threads",
+            runtime.modules.len(),
+            start.elapsed().as_secs_f64() * 1000.0,
+            rayon::current_num_threads(),
+        );
+    }
+    for chunk in &chunk_graph.chunks {
+        let filename = chunk.filename(context.clone());
+        let (code, source_map) = match chunk.kind {
+            ChunkKind::Entry | ChunkKind::Worker => {
+                runtime.render(chunk, &chunk_graph, &filename, context.clone())
+            }
+            ChunkKind::Async | ChunkKind::Common => {
+                runtime.render_chunk(chunk, &filename, context.clone())
+            }
+        };
+        files.extend(emit_file(&filename, code, source_map, context.clone())?);
+    }
+    let modules = runtime
+        .modules
+        .into_iter()
+        .map(|(path, code)| (runtime.module_ids[&path].clone(), code))
+        .collect();
+    Ok(Output { files, modules })
+}
+
+struct Output {
+    files: Vec<OutputFile>,
+    /// Code of each module by id, to diff for hot updates
+    modules: HashMap<String, String>,
+}
+
+/// A file emitted by `generate`, relative to the output directory.
+#[derive(PartialEq)]
+struct OutputFile {
+    filename: String,
+    content: Vec<u8>,
+}
+
+/// An output file with its source map and extracted licenses, minifying it
+/// first when enabled.
+fn emit_file(
+    filename: &str,
+    mut code: String,
+    mut source_map: Option<sourcemap::SourceMap>,
+    context: Arc<Context>,
+) -> Result<Vec<OutputFile>, GenerateError> {
+    let mut files = vec![];
+    let mut licenses = vec![];
+    if context.config.minify() {
+        (code, source_map, licenses) = minify(code, source_map, filename, context.clone())?;
+    }
+    if !licenses.is_empty() {
+        files.push(OutputFile {
+            filename: format!("{}.LICENSE.txt", filename),
+            content: licenses.join("\n\n").into_bytes(),
+        });
+    }
+    if let (Some(mode), Some(source_map)) = (context.config.sourcemap, source_map) {
+        let mut buf = vec![];
+        // serializing into a `Vec` does not fail
+        source_map.to_writer(&mut buf).unwrap();
+        let map_filename = format!("{}.map", filename);
+        match mode {
+            SourceMapMode::Inline => {
+                let data = base64::engine::general_purpose::STANDARD.encode(&buf);
+                code.push_str(&format!(
+                    "\n//# sourceMappingURL=data:application/json;charset=utf-8;base64,{}",
+                    data
+                ));
+            }
+            SourceMapMode::External | SourceMapMode::Hidden => {
+                if mode == SourceMapMode::External {
+                    code.push_str(&format!("\n//# sourceMappingURL={}", map_filename));
+                }
+                files.push(OutputFile {
+                    filename: map_filename,
+                    content: buf,
+                });
+            }
+        }
+    }
+    files.push(OutputFile {
+        filename: filename.to_string(),
+        content: code.into_bytes(),
+    });
+    Ok(files)
+}
+
+fn write_output(files: &[OutputFile], context: Arc<Context>) -> Result<(), GenerateError> {
+    let output_dir = &context.output;
+    std::fs::create_dir_all(output_dir).map_err(|error| GenerateError::Write {
+        path: output_dir.clone(),
+        error,
+    })?;
+    files.iter().try_for_each(|file| {
+        let output = output_dir.join(&file.filename);
+        std::fs::write(&output, &file.content).map_err(|error| GenerateError::Write {
+            path: output,
+            error,
+        })
+    })
+}
+
+/// Compresses and mangles the rendered bundle, moving license comments
+/// (`/*! */`, `@license`, `@preserve`) out of it.
+fn minify(
+    code: String,
+    source_map: Option<sourcemap::SourceMap>,
+    filename: &str,
+    context: Arc<Context>,
+) -> Result<(String, Option<sourcemap::SourceMap>, Vec<String>), GenerateError> {
+    let options = &context.config.minify_options;
+    let cm = context.cm.clone();
+    let comments = SwcComments::default();
+    let file = cm.new_source_file(FileName::Custom(filename.to_string()), code);
+    let lexer = Lexer::new(
+        Syntax::Es(Default::default()),
+        EsVersion::latest(),
+        StringInput::from(&*file),
+        Some(&comments),
+    );
+    // a module when externals are imported
+    let program =
+        Parser::new_from(lexer)
+            .parse_program()
+            .map_err(|error| GenerateError::Minify {
+                message: format!("{:?}", error.kind()),
+            })?;
+
+    let mut licenses = comments
+        .leading
+        .iter()
+        .chain(comments.trailing.iter())
+        .flat_map(|entry| entry.value().clone())
+        .filter(|comment| {
+            comment.text.starts_with('!')
+                || comment.text.contains("@license")
+                || comment.text.contains("@preserve")
+        })
+        .map(|comment| (comment.span.lo, comment))
+        .collect::<Vec<_>>();
+    licenses.sort_by_key(|(pos, _)| *pos);
+    licenses.dedup_by_key(|(pos, _)| *pos);
+    let licenses = licenses
+        .into_iter()
+        .map(|(_, comment)| match comment.kind {
+            CommentKind::Block => format!("/*{}*/", comment.text),
+            CommentKind::Line => format!("//{}", comment.text),
+        })
+        .collect::<Vec<_>>();
+
+    // the minifier reports diagnostics through `HANDLER`
+    let program = try_with_handler(cm.clone(), Default::default(), |_| {
+        Ok(GLOBALS.set(&context.globals, || {
+            let unresolved_mark = Mark::new();
+            let top_level_mark = Mark::new();
+            let program = program.fold_with(&mut resolver(unresolved_mark, top_level_mark, false));
+            let program = optimize(
+                program,
+                cm.clone(),
+                None,
+                None,
+                &MinifyOptions {
+                    compress: Some(CompressOptions {
+                        drop_console: options.drop_console,
+                        keep_classnames: options.keep_class_names,
+                        keep_fnames: options.keep_fn_names,
+                        ..Default::default()
+                    }),
+                    mangle: Some(MangleOptions {
+                        keep_class_names: options.keep_class_names,
+                        keep_fn_names: options.keep_fn_names,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                &ExtraOptions {
+                    unresolved_mark,
+                    top_level_mark,
+                },
+            );
+            program.fold_with(&mut fixer(None))
+        }))
+    })
+    .map_err(|error| GenerateError::Minify {
+        message: error.to_string(),
+    })?;
+
+    let mut buf = vec![];
+    let mut mappings = vec![];
+    let mut banner = String::new();
+    if !licenses.is_empty() {
+        banner = format!(
+            "/*! For license information please see {}.LICENSE.txt */\n",
+            filename
+        );
+        buf.extend_from_slice(banner.as_bytes());
+    }
+    {
+        let mut emitter = Emitter {
+            cfg: codegen::Config::default().with_minify(true),
+            cm: cm.clone(),
+            comments: None,
+            wr: Box::new(JsWriter::new(
+                cm.clone(),
+                "\n",
+                &mut buf,
+                Some(&mut mappings),
+            )),
+        };
+        emitter
+            .emit_program(&program)
+            .map_err(|error| GenerateError::Codegen {
+                path: filename.to_string(),
+                error,
+            })?;
+    }
+    // chain through the map of the unminified bundle
+    let source_map = source_map.map(|source_map| {
+        let minified = cm.build_source_map_from(&mappings, Some(&source_map));
+        let mut builder = SourceMapBuilder::new(Some(filename));
+        add_source_map(&mut builder, &minified, banner.lines().count() as u32);
+        builder.into_sourcemap()
+    });
+    // the emitter only writes valid utf-8
+    Ok((String::from_utf8(buf).unwrap(), source_map, licenses))
+}
+
+struct Runtime {
+    modules: HashMap<String, String>,
+    /// Per module source maps, only filled when source maps are enabled
+    source_maps: HashMap<String, sourcemap::SourceMap>,
+    /// Concatenated ES modules and their source map
+    hoisted: Option<(String, Option<sourcemap::SourceMap>)>,
+    /// Module path to the id it is defined with
+    module_ids: HashMap<String, String>,
+    /// Id of `react-refresh/runtime` when React Refresh is on
+    refresh_runtime: Option<String>,
+    /// Externals imported by any module, by specifier
+    externals: BTreeMap<String, ExternalModule>,
+}
+
+impl Runtime {
+    /// Renders the entry chunk with the runtime, and its source map when
+    /// source maps are enabled.
+    fn render(
+        &self,
+        chunk: &Chunk,
+        chunk_graph: &ChunkGraph,
+        filename: &str,
+        context: Arc<Context>,
+    ) -> (String, Option<sourcemap::SourceMap>) {
+        let mut ret = vec![];
+        ret.push(
+            r#"
+const modules = new Map();
+const define = (name, moduleFactory) => {
+  modules.set(name, moduleFactory);
+};
+
+const moduleCache = new Map();
+const requireModule = (name) => {
+  if (moduleCache.has(name)) {
+    return moduleCache.get(name).exports;
+  }
+
+  if (!modules.has(name)) {
+    throw new Error(`Module '${name}' does not exist.`);
+  }
+
+  const moduleFactory = modules.get(name);
+  const module = {
+    id: name,
+    exports: {},
+  };
+  moduleCache.set(name, module);
+  __run__;
+  return module.exports;
+};
+        "#
+            // modules get their own `require` to track importers for hot updates
+            .replace(
+                "__run__",
+                if context.react_refresh() {
+                    "runWithRefresh(module, () => moduleFactory(module, module.exports, createHotRequire(module)))"
+                } else if context.hmr {
+                    "moduleFactory(module, module.exports, createHotRequire(module))"
+                } else {
+                    "moduleFactory(module, module.exports, requireModule)"
+                },
+            ),
+        );
+        ret.push(format!(
+            "requireModule.publicPath = {};",
+            js_string(&context.config.public_path)
+        ));
+        if chunk_graph.chunks.len() > 1 {
+            let chunk_files = chunk_graph
+                .chunks
+                .iter()
+                .filter(|chunk| matches!(chunk.kind, ChunkKind::Async | ChunkKind::Common))
+                .map(|chunk| {
+                    let filename = chunk.filename(context.clone());
+                    format!("{}: {}", js_string(&chunk.id), js_string(&filename))
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            ret.push(format!("const chunkFiles = {{ {} }};", chunk_files));
+            ret.push(
+                r#"const loadedChunks = new Set();
+const chunkPromises = new Map();
+const registerChunk = ([id, chunkModules]) => {
+  Object.keys(chunkModules).forEach((name) => define(name, chunkModules[name]));
+  loadedChunks.add(id);
+};
+const chunkQueue = (globalThis.makoChunks = globalThis.makoChunks || []);
+chunkQueue.forEach(registerChunk);
+chunkQueue.push = registerChunk;
+const ensureChunk = (id) => {
+  if (loadedChunks.has(id)) {
+    return Promise.resolve();
+  }
+  if (!chunkPromises.has(id)) {
+    const file = chunkFiles[id];
+    const promise = new Promise((resolve, reject) => {
+      if (typeof document === 'undefined') {
+        // node, chunks are next to the bundle
+        (typeof require === 'function'
+          ? Promise.resolve().then(() => require(`${__dirname}/${file}`))
+          : import(`./${file}`)
+        ).then(resolve, reject);
+        return;
+      }
+      const script = document.createElement('script');
+      script.src = requireModule.publicPath + file;
+      script.onload = resolve;
+      script.onerror = () => reject(new Error(`Chunk '${id}' failed to load.`));
+      document.head.appendChild(script);
+    }).then(() => {
+      if (!loadedChunks.has(id)) {
+        throw new Error(`Chunk '${id}' failed to load.`);
+      }
+    });
+    // a failed chunk is retried on the next import
+    promise.catch(() => chunkPromises.delete(id));
+    chunkPromises.set(id, promise);
+  }
+  return chunkPromises.get(id);
+};
+requireModule.ensureChunk = ensureChunk;"#
+                    .to_string(),
+            );
+        }
+        if context.hmr {
+            ret.push(
+                r#"const hotData = new Map();
+const moduleParents = new Map();
+const createHotRequire = (module) => {
+  const name = module.id;
+  if (!moduleParents.has(name)) {
+    moduleParents.set(name, new Set());
+  }
+  const hot = (module.hot = {
+    data: hotData.get(name),
+    selfAccepted: false,
+    acceptedDeps: new Map(),
+    disposeHandlers: [],
+    accept(deps, callback) {
+      if (deps === undefined || typeof deps === 'function') {
+        hot.selfAccepted = deps || true;
+      } else {
+        [].concat(deps).forEach((dep) => hot.acceptedDeps.set(dep, callback || (() => {})));
+      }
+    },
+    dispose(callback) {
+      hot.disposeHandlers.push(callback);
+    },
+    invalidate() {
+      // bubble to the parents even when self accepted
+      setTimeout(() => applyHotUpdate({}, [name]));
+    },
+  });
+  const require = (dep) => {
+    const exports = requireModule(dep);
+    if (moduleParents.has(dep)) {
+      moduleParents.get(dep).add(name);
+    }
+    return exports;
+  };
+  require.publicPath = requireModule.publicPath;
+  require.ensureChunk = requireModule.ensureChunk;
+  return require;
+};
+const hotReload = (reason) => {
+  console.warn(`[HMR] ${reason}, reloading`);
+  if (typeof location !== 'undefined') {
+    location.reload();
+  }
+};
+const applyHotUpdate = (factories, invalidated) => {
+  Object.keys(factories).forEach((name) => define(name, factories[name]));
+  // modules never required yet pick up the new factory when they are
+  const queue = Object.keys(factories).concat(invalidated).filter((name) => moduleCache.has(name));
+  const outdated = new Set();
+  const selfAccepted = [];
+  const acceptedDeps = new Map();
+  while (queue.length) {
+    const name = queue.shift();
+    if (outdated.has(name)) {
+      continue;
+    }
+    outdated.add(name);
+    const module = moduleCache.get(name);
+    if (module.hot.selfAccepted && !invalidated.includes(name)) {
+      selfAccepted.push(name);
+      continue;
+    }
+    const parents = [...moduleParents.get(name)].filter((parent) => moduleCache.has(parent));
+    if (!parents.length) {
+      return hotReload(`${name} is not accepted`);
+    }
+    parents.forEach((parent) => {
+      if (moduleCache.get(parent).hot.acceptedDeps.has(name)) {
+        acceptedDeps.set(parent, (acceptedDeps.get(parent) || []).concat(name));
+      } else {
+        queue.push(parent);
+      }
+    });
+  }
+  outdated.forEach((name) => {
+    const data = {};
+    moduleCache.get(name).hot.disposeHandlers.forEach((handler) => handler(data));
+    hotData.set(name, data);
+    moduleCache.delete(name);
+  });
+  selfAccepted.forEach((name) => {
+    try {
+      requireModule(name);
+    } catch (err) {
+      const onError = moduleCache.has(name) && moduleCache.get(name).hot.selfAccepted;
+      if (typeof onError !== 'function') {
+        return hotReload(`${name} failed to update: ${err}`);
+      }
+      onError(err);
+    }
+  });
+  acceptedDeps.forEach((deps, parent) => {
+    const { acceptedDeps: callbacks } = moduleCache.get(parent).hot;
+    try {
+      deps.forEach((dep) => requireModule(dep));
+      deps.forEach((dep) => callbacks.get(dep)(deps));
+    } catch (err) {
+      hotReload(`${deps.join(', ')} failed to update: ${err}`);
+    }
+  });
+};
+globalThis.makoHotUpdate = (factories) => applyHotUpdate(factories, []);
+if (typeof WebSocket !== 'undefined' && typeof location !== 'undefined') {
+  const protocol = location.protocol === 'https:' ? 'wss:' : 'ws:';
+  const socket = new WebSocket(`${protocol}//${location.host}/__mako_hmr`);
+  socket.onmessage = (event) => {
+    const message = JSON.parse(event.data);
+    if (message.type === 'reload') {
+      location.reload();
+    } else if (message.type === 'update') {
+      const script = document.createElement('script');
+      script.src = requireModule.publicPath + message.file;
+      document.head.appendChild(script);
+    }
+  };
+}"#
+                .to_string(),
+            );
+        }
+        if context.react_refresh() {
+            ret.push(
+                r#"// until the refresh runtime is loaded, components are not registered
+globalThis.$RefreshReg$ = () => {};
+globalThis.$RefreshSig$ = () => (type) => type;
+let refreshRuntime;
+let refreshTimeout;
+const isRefreshBoundary = (exports) => {
+  if (refreshRuntime.isLikelyComponentType(exports)) {
+    return true;
+  }
+  if (exports == null || typeof exports !== 'object') {
+    return false;
+  }
+  const names = Object.keys(exports);
+  return names.length > 0 && names.every((name) => refreshRuntime.isLikelyComponentType(exports[name]));
+};
+const runWithRefresh = (module, run) => {
+  if (!refreshRuntime) {
+    return run();
+  }
+  const { $RefreshReg$, $RefreshSig$ } = globalThis;
+  globalThis.$RefreshReg$ = (type, id) => refreshRuntime.register(type, `${module.id} ${id}`);
+  globalThis.$RefreshSig$ = refreshRuntime.createSignatureFunctionForTransform;
+  try {
+    run();
+  } finally {
+    globalThis.$RefreshReg$ = $RefreshReg$;
+    globalThis.$RefreshSig$ = $RefreshSig$;
+  }
+  // modules only exporting components are updated in place
+  const wasBoundary = module.hot.data && module.hot.data.refreshBoundary;
+  if (isRefreshBoundary(module.exports)) {
+    module.hot.dispose((data) => {
+      data.refreshBoundary = true;
+    });
+    module.hot.accept();
+    if (wasBoundary) {
+      clearTimeout(refreshTimeout);
+      refreshTimeout = setTimeout(() => refreshRuntime.performReactRefresh(), 30);
+    }
+  } else if (wasBoundary) {
+    module.hot.invalidate();
+  }
+};"#
+                    .to_string(),
+            );
+        }
+        let mut imports = vec![];
+        self.externals.iter().for_each(|(specifier, external)| {
+            let exports = match external.kind {
+                ExternalKind::Global => external
+                    .name
+                    .split('.')
+                    .fold("globalThis".to_string(), |object, key| {
+                        format!("{}[{}]", object, js_string(key))
+                    }),
+                ExternalKind::CommonJs => format!("require({})", js_string(&external.name)),
+                ExternalKind::Module => {
+                    let binding = format!("__mako_external_{}", imports.len());
+                    imports.push(format!(
+                        "import * as {} from {};",
+                        binding,
+                        js_string(&external.name)
+                    ));
+                    // namespace objects are ES modules for the interop helpers
+                    format!("Object.assign({{ __esModule: true }}, {})", binding)
+                }
+                ExternalKind::Empty => "{}".to_string(),
+            };
+            ret.push(format!(
+                "define({}, function (module) {{ module.exports = {}; }});",
+                js_string(specifier),
+                exports
+            ));
+        });
+        // imports have to come first, there are no source maps to shift yet
+        if !imports.is_empty() {
+            ret.insert(0, imports.join("\n"));
+        }
+        let mut source_map = SourceMapBuilder::new(Some(filename));
+        self.render_modules(chunk, &mut ret, &mut source_map, |id, code| {
+            format!(
+                "define({}, function (module, exports, require) {{\n{}\n}});",
+                js_string(id),
+                code
+            )
+        });
+        if let Some(id) = &self.refresh_runtime {
+            // before React is loaded
+            ret.push(format!(
+                "refreshRuntime = requireModule({});\nrefreshRuntime.injectIntoGlobalHook(globalThis);",
+                js_string(id)
+            ));
+        }
+        let entries = match chunk.kind {
+            ChunkKind::Worker => vec![chunk.modules[0].clone()],
+            _ => context
+                .entries
+                .iter()
+                .map(|entry| entry.to_string_lossy().to_string())
+                .collect(),
+        };
+        let mut hoisted = self
+            .hoisted
+            .as_ref()
+            .filter(|_| chunk.kind == ChunkKind::Entry);
+        entries.iter().for_each(|entry| {
+            if self.modules.contains_key(entry) {
+                ret.push(format!(
+                    "requireModule({});",
+                    js_string(&self.module_ids[entry])
+                ));
+            } else if let Some((code, module_map)) = hoisted.take() {
+                // hoisted entries all run at the first one, in a single scope
+                if let Some(module_map) = module_map {
+                    let line: usize = ret.iter().map(|code| code.matches('\n').count() + 1).sum();
+                    add_source_map(&mut source_map, module_map, line as u32 + 2);
+                }
+                ret.push(format!(
+                    "(function () {{\n\"use strict\";\n{}\n}})();",
+                    code
+                ));
+            }
+        });
+        let source_map = context
+            .config
+            .sourcemap
+            .map(|_| source_map.into_sourcemap());
+        (ret.join("\n"), source_map)
+    }
+
+    /// Renders an async or common chunk, registering its modules with the
+    /// runtime of the entry chunk once loaded.
+    fn render_chunk(
+        &self,
+        chunk: &Chunk,
+        filename: &str,
+        context: Arc<Context>,
+    ) -> (String, Option<sourcemap::SourceMap>) {
+        let mut ret = vec![format!(
+            "(globalThis.makoChunks = globalThis.makoChunks || []).push([{}, {{",
+            js_string(&chunk.id)
+        )];
+        let mut source_map = SourceMapBuilder::new(Some(filename));
+        self.render_modules(chunk, &mut ret, &mut source_map, |id, code| {
+            format!(
+                "{}: function (module, exports, require) {{\n{}\n}},",
+                js_string(id),
+                code
+            )
+        });
+        ret.push("}]);".to_string());
+        let source_map = context
+            .config
+            .sourcemap
+            .map(|_| source_map.into_sourcemap());
+        (ret.join("\n"), source_map)
+    }
+
+    fn render_modules(
+        &self,
+        chunk: &Chunk,
+        ret: &mut Vec<String>,
+        source_map: &mut SourceMapBuilder,
+        wrap: impl Fn(&str, &str) -> String,
+    ) {
+        chunk.modules.iter().for_each(|path| {
+            // hoisted modules are rendered separately
+            let Some(code) = self.modules.get(path) else {
+                return;
+            };
+            if let Some(module_map) = self.source_maps.get(path) {
+                // the module code starts on the line after the wrapper
+                let line: usize = ret.iter().map(|code| code.matches('\n').count() + 1).sum();
+                add_source_map(source_map, module_map, line as u32 + 1);
+            }
+            ret.push(wrap(&self.module_ids[path], code));
+        });
+    }
+}
+
+fn tramsform_again(ast: &mut Ast, context: Arc<Context>) {
+    GLOBALS.set(&context.globals, || {
+        HELPERS.set(&Helpers::new(false), || {
+            // common_js would `require('url')` for it, which the runtime doesn't provide
+            ast.ast.visit_mut_with(&mut ImportMetaUrlReplacer {
+                platform: context.config.platform,
+                unresolved_ctxt: SyntaxContext::empty().apply_mark(ast.unresolved_mark),
+            });
+            ast.ast.visit_mut_with(&mut common_js(
+                ast.unresolved_mark,
+                Default::default(),
+                FeatureFlag::empty(),
+                Some(&context.comments),
+            ));
+            // interop helpers used by common_js
+            ast.ast
+                .visit_mut_with(&mut inject_helpers(ast.unresolved_mark));
+            // transforms may emit nodes that need parens or renaming before codegen
+            ast.ast.visit_mut_with(&mut hygiene());
+            ast.ast.visit_mut_with(&mut fixer(Some(&context.comments)));
+        });
+    });
+}
+
+/// Ids modules are defined and required with, following `moduleIds`.
+fn module_ids(module_graph: &ModuleGraph, context: Arc<Context>) -> HashMap<String, String> {
+    let relative = |path: &str| {
+        let relative = relative_path(&context.root, Path::new(path));
+        if relative.starts_with("../") {
+            relative
+        } else {
+            format!("./{}", relative)
+        }
+    };
+    match context.config.module_ids() {
+        ModuleIds::Path => module_graph
+            .modules
+            .keys()
+            .map(|path| (path.clone(), relative(path)))
+            .collect(),
+        ModuleIds::Numeric => module_graph
+            .modules
+            .keys()
+            .enumerate()
+            .map(|(index, path)| (path.clone(), index.to_string()))
+            .collect(),
+        ModuleIds::Hash => hash_ids(
+            module_graph
+                .modules
+                .keys()
+                .map(|path| (path.clone(), relative(path)))
+                .collect(),
+        ),
+    }
+}
+
+/// Ids of 8 hex digits hashed from the root-relative path alone, so a module
+/// keeps its id while other modules come and go. Modules whose short ids
+/// collide get the whole 16 digit hash instead.
+fn hash_ids(relative_paths: HashMap<String, String>) -> HashMap<String, String> {
+    let hashes = relative_paths
+        .into_iter()
+        .map(|(path, relative)| (path, format!("{:016x}", seahash::hash(relative.as_bytes()))))
+        .collect::<Vec<_>>();
+    let mut short = HashMap::<&str, usize>::new();
+    hashes
+        .iter()
+        .for_each(|(_, hash)| *short.entry(&hash[..8]).or_default() += 1);
+    hashes
+        .iter()
+        .map(|(path, hash)| {
+            let id = if short[&hash[..8]] > 1 {
+                hash.clone()
+            } else {
+                hash[..8].to_string()
+            };
+            (path.clone(), id)
+        })
+        .collect()
+}
+
+/// Points dependencies at module ids, and URL and worker dependencies at the
+/// URLs of the emitted asset or worker chunk.
+fn replace_deps(
+    ast: &mut Ast,
+    deps: &[(Dependency, PathBuf)],
+    module_ids: &HashMap<String, String>,
+    urls: &HashMap<String, String>,
+) {
+    let deps = deps
+        .iter()
+        .filter_map(|(dep, path)| {
+            let path = path.to_string_lossy();
+            let id = match dep.kind {
+                DependencyKind::Url | DependencyKind::Worker => urls.get(&*path)?,
+                _ => module_ids.get(&*path)?,
+            };
+            Some(((dep.span, dep.specifier.clone()), id.clone()))
+        })
+        .collect::<HashMap<_, _>>();
+    ast.ast.visit_mut_with(&mut DepsReplacer { deps });
+}
+
+/// Rewrites the specifier string of every analyzed dependency, matched by span
+/// and specifier since injected imports, like the JSX runtime, share the dummy
+/// span with other generated strings.
+struct DepsReplacer {
+    deps: HashMap<(Span, String), String>,
+}
+
+impl VisitMut for DepsReplacer {
+    fn visit_mut_str(&mut self, str: &mut Str) {
+        if let Some(path) = self.deps.get(&(str.span, str.value.to_string())) {
+            *str = path.clone().into();
+        }
+    }
+}
+
+/// Replaces `import.meta.url` with the URL of the page, or of the bundle in
+/// Node, that URL and worker dependencies are rewritten relative to.
+struct ImportMetaUrlReplacer {
+    platform: Platform,
+    unresolved_ctxt: SyntaxContext,
+}
+
+impl VisitMut for ImportMetaUrlReplacer {
+    fn visit_mut_expr(&mut self, expr: &mut Expr) {
+        if !is_import_meta_url(expr) {
+            expr.visit_mut_children_with(self);
+            return;
+        }
+        let global = |sym: &str| {
+            Box::new(Expr::Ident(Ident::new(
+                sym.into(),
+                DUMMY_SP.with_ctxt(self.unresolved_ctxt),
+            )))
+        };
+        *expr = match self.platform {
+            Platform::Browser => Expr::Member(MemberExpr {
+                span: DUMMY_SP,
+                obj: global("location"),
+                prop: MemberProp::Ident(Ident::new("href".into(), DUMMY_SP)),
+            }),
+            Platform::Node => Expr::Bin(BinExpr {
+                span: DUMMY_SP,
+                op: BinaryOp::Add,
+                left: Box::new(Expr::Lit(Lit::Str("file://".into()))),
+                right: global("__filename"),
+            }),
+        };
+    }
+}
+
+/////////////////////////////////////////
+// Watch
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Rebuilds whenever a module changes, until the process is killed, passing
+/// the output to `emit`. Build errors are reported and the failed modules
+/// retried on the next change.
+fn watch(context: Arc<Context>, mut emit: impl FnMut(Output) -> Result<(), GenerateError>) -> ! {
+    let mut module_graph = ModuleGraph {
+        modules: IndexMap::new(),
+    };
+    let mut dirty = HashSet::new();
+    // what the modules were built from, changes during a rebuild are
+    // compared against it
+    let mut mtimes = HashMap::new();
+    let mut built = false;
+    loop {
+        let start = Instant::now();
+        let changed = dirty.len();
+        for path in watched_paths(&watched_files(&module_graph, &dirty)) {
+            mtimes.entry(path).or_insert_with_key(|path| modified(path));
+        }
+        let errors = rebuild(
+            &mut module_graph,
+            std::mem::take(&mut dirty),
+            context.clone(),
+        );
+        if errors.is_empty() {
+            // generate mutates the graph, keep the built one for the next rebuild
+            let emitted = generate(&mut module_graph.clone(), context.clone()).and_then(&mut emit);
+            match emitted {
+                Ok(()) if built => println!(
+                    "Rebuilt {} changed file(s) in {:.2}ms",
+                    changed,
+                    start.elapsed().as_secs_f64() * 1000.0
+                ),
+                Ok(()) => println!("Built in {:.2}ms", start.elapsed().as_secs_f64() * 1000.0),
+                Err(error) => eprintln!("error: {}", CompileError::Generate(error)),
+            }
+            built = true;
+        } else {
+            dirty.extend(
+                errors
+                    .iter()
+                    .map(|error| error.module().to_string_lossy().to_string()),
+            );
+            eprintln!("error: {}", CompileError::Build(errors));
+        }
+        mtimes.extend(
+            module_graph
+                .modules
+                .iter()
+                .map(|(path, module)| (split_query(Path::new(path)).0.to_path_buf(), module.mtime)),
+        );
+        println!("Watching for changes...");
+        // a new file only shows as a change of its directory, which is
+        // enough to retry modules that failed to resolve it
+        loop {
+            let files = watched_files(&module_graph, &dirty);
+            let changed = wait_for_changes(&files, &mut mtimes);
+            // a file imported with different queries is several modules
+            let paths = module_graph.modules.keys().cloned().collect::<Vec<_>>();
+            dirty.extend(paths.into_iter().filter(|path| {
+                changed.contains(&*split_query(Path::new(path)).0.to_string_lossy())
+            }));
+            if !dirty.is_empty() {
+                break;
+            }
+        }
+    }
+}
+
+/// Rebuilds changed modules and the modules they newly import, drops modules
+/// no longer imported and keeps the previous version of modules that failed.
+fn rebuild(
+    module_graph: &mut ModuleGraph,
+    changed: HashSet<String>,
+    context: Arc<Context>,
+) -> Vec<BuildError> {
+    let mut dirty = HashSet::new();
+    for path in changed {
+        if split_query(Path::new(&path)).0.is_file() {
+            dirty.insert(path);
+            continue;
+        }
+        // deleted, its importers have to resolve it again
+        module_graph.modules.shift_remove(&path);
+        dirty.extend(
+            module_graph
+                .modules
+                .iter()
+                .filter(|(_, module)| {
+                    module
+                        .deps
+                        .iter()
+                        .any(|(_, resolved)| resolved.to_string_lossy() == path)
+                })
+                .map(|(importer, _)| importer.clone()),
+        );
+    }
+    // stylesheets embed the URLs of their assets, which change with the content
+    let stylesheets = module_graph
+        .modules
+        .iter()
+        .filter(|(path, module)| {
+            extension(Path::new(path)) == "css"
+                && module
+                    .deps
+                    .iter()
+                    .any(|(_, resolved)| dirty.contains(&*resolved.to_string_lossy()))
+        })
+        .map(|(path, _)| path.clone())
+        .collect::<Vec<_>>();
+    dirty.extend(stylesheets);
+    let state = BuildState {
+        context: context.clone(),
+        seen: Mutex::new(
+            module_graph
+                .modules
+                .keys()
+                .filter(|path| !dirty.contains(*path))
+                .cloned()
+                .collect(),
+        ),
+        modules: Default::default(),
+        errors: Default::default(),
+        timings: Default::default(),
+    };
+    rayon::scope(|scope| {
+        dirty
+            .iter()
+            .map(PathBuf::from)
+            .chain(context.entries.iter().cloned())
+            .for_each(|path| schedule_build(scope, path, &state));
+    });
+    let mut modules = std::mem::take(&mut module_graph.modules)
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+    modules.extend(state.modules.into_inner().unwrap());
+    module_graph.modules = discovery_order(modules, &context.entries);
+    let mut errors = state.errors.into_inner().unwrap();
+    errors.sort_by_cached_key(|error| error.to_string());
+    errors
+}
+
+/// Files of the modules in the graph and of the modules that failed.
+fn watched_files(module_graph: &ModuleGraph, dirty: &HashSet<String>) -> HashSet<PathBuf> {
+    module_graph
+        .modules
+        .keys()
+        .chain(dirty.iter())
+        .map(|path| split_query(Path::new(path)).0.to_path_buf())
+        .collect()
+}
+
+/// `files` and their directories.
+fn watched_paths(files: &HashSet<PathBuf>) -> Vec<PathBuf> {
+    files
+        .iter()
+        .chain(
+            files
+                .iter()
+                .filter_map(|file| file.parent())
+                .map(Path::to_path_buf)
+                .collect::<HashSet<_>>()
+                .iter(),
+        )
+        .cloned()
+        .collect()
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// Polls the modification times of `files` and their directories, returning
+/// the changed files once a burst of changes has settled. Times are compared
+/// against `mtimes`, which is kept up to date, paths missing from it against
+/// their time when called.
+fn wait_for_changes(
+    files: &HashSet<PathBuf>,
+    mtimes: &mut HashMap<PathBuf, Option<SystemTime>>,
+) -> HashSet<String> {
+    let paths = watched_paths(files);
+    for path in &paths {
+        mtimes
+            .entry(path.clone())
+            .or_insert_with_key(|path| modified(path));
+    }
+    let mut changed = None::<HashSet<String>>;
+    loop {
+        let updated = paths
+            .iter()
+            .filter(|path| {
+                let current = modified(path);
+                mtimes.insert(path.to_path_buf(), current) != Some(current)
+            })
+            .collect::<Vec<_>>();
+        match (&mut changed, updated.is_empty()) {
+            (Some(changed), true) => return std::mem::take(changed),
+            (None, true) => {}
+            (changed, false) => changed.get_or_insert_with(HashSet::new).extend(
+                updated
+                    .into_iter()
+                    .filter(|path| files.contains(*path))
+                    .map(|path| path.to_string_lossy().to_string()),
+            ),
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
 }
 
 /////////////////////////////////////////
-// Build Stage
+// Dev Server
 
-struct BuildQueue {
-    queue: Vec<PathBuf>,
+/// Output of the last successful build, served from memory.
+#[derive(Default)]
+struct DevServerState {
+    files: RwLock<HashMap<String, Vec<u8>>>,
+    /// Websocket connections of the HMR clients
+    hmr_clients: Mutex<Vec<TcpStream>>,
 }
 
-struct BuildParams {
-    path: PathBuf,
-    context: Arc<Context>,
+struct Request {
+    method: String,
+    /// Path and query
+    target: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
 }
 
-fn build(params: BuildParams) -> ModuleGraph {
-    let mut build_queue = BuildQueue {queue: vec![params.path],};
-    let mut module_graph = ModuleGraph {modules: HashMap::new(),};
-    let context = params.context;
-    while let Some(path) = build_queue.queue.pop() {
-        if module_graph.modules.contains_key(&path.to_string_lossy().to_string()) { continue; }
-        // load
-        let content = load(&path);
-        // parse
-        let mut ast = parse(content, &path, context.clone());
-        // transform
-        transform(&mut ast, context.clone());
-        // analyze_deps
-        let deps = analyze_deps(&ast);
-        // resolve
-        let mut hash_deps = HashMap::<String, PathBuf>::new();
-        deps.iter().for_each(|dep| {
-            let resolved = resolve(&path, dep);
-            hash_deps.insert(dep.to_string(), resolved);
-        });
-        module_graph.modules.insert(
-            path.to_string_lossy().to_string(),
-            Module {
-                ast,
-                deps: hash_deps.clone(),
-            },
-        );
-        build_queue.queue.extend(hash_deps.into_values());
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
     }
-    module_graph
-}
-
-fn load(path: &PathBuf) -> String {
-    std::fs::read_to_string(path).unwrap()
-}
-
-fn parse(content: String, path: &PathBuf, context: Arc<Context>) -> Ast {
-    let ast = code_to_ast(content, path, context.cm.clone(), &context.comments);
-    GLOBALS.set(&context.globals, || Ast {
-        ast,
-        unresolved_mark: Mark::new(),
-        top_level_mark: Mark::new(),
-    })
 }
 
-fn transform(ast: &mut Ast, context: Arc<Context>) {
-    let unresolved_mark = ast.unresolved_mark;
-    let top_level_mark = ast.top_level_mark;
-    let ast = &mut ast.ast;
-    let comments = &context.comments;
-    GLOBALS.set(&context.globals, || {
-        HELPERS.set(&Helpers::new(true), || {
-            let resolver = resolver(unresolved_mark, top_level_mark, false);
-            let preset_env = preset_env::preset_env(
-                unresolved_mark,
-                Some(comments),
-                Default::default(),
-                Default::default(),
-                &mut Default::default(),
-            );
-            let body = ast.body.take();
-            let module = SwcModule {
-                span: ast.span,
-                shebang: ast.shebang.clone(),
-                body,
-            };
-            let mut folders = chain!(resolver, preset_env);
-            ast.body = folders.fold_module(module).body;
+/// Serves the output from memory on localhost while rebuilding on changes,
+/// only returns when the server can't start.
+fn serve(context: Arc<Context>) -> Result<(), String> {
+    let port = context.config.dev_server.port;
+    let listener = TcpListener::bind(("localhost", port))
+        .map_err(|err| format!("failed to listen on localhost:{}: {}", port, err))?;
+    println!("Serving on http://localhost:{}", port);
+    let state = Arc::new(DevServerState::default());
+    let server_state = state.clone();
+    let server_context = context.clone();
+    std::thread::spawn(move || {
+        listener.incoming().flatten().for_each(|stream| {
+            let state = server_state.clone();
+            let context = server_context.clone();
+            std::thread::spawn(move || {
+                if let Err(err) = handle_connection(stream, &state, context.clone()) {
+                    if context.config.debug {
+                        eprintln!("dev server: {}", err);
+                    }
+                }
+            });
         });
     });
-}
-
-fn analyze_deps(ast: &Ast) -> Vec<String> {
-    let mut deps = vec![];
-    ast.ast.body.iter().for_each(|item| {
-        if let ModuleItem::ModuleDecl(decl) = item {
-            if let ModuleDecl::Import(import) = decl {
-                deps.push(import.src.value.to_string());
+    let mut previous = None::<Output>;
+    let mut updates = 0;
+    watch(context.clone(), |output| {
+        let mut files = output
+            .files
+            .iter()
+            .map(|file| (file.filename.clone(), file.content.clone()))
+            .collect::<HashMap<_, _>>();
+        let message = match &previous {
+            Some(previous) if context.hmr => {
+                let changed = output
+                    .modules
+                    .iter()
+                    .filter(|(id, code)| previous.modules.get(*id) != Some(*code))
+                    .collect::<BTreeMap<_, _>>();
+                let filenames = |output: &Output| {
+                    output
+                        .files
+                        .iter()
+                        .map(|file| file.filename.clone())
+                        .collect::<HashSet<_>>()
+                };
+                if filenames(previous) != filenames(&output) {
+                    // the runtime doesn't know the new chunks
+                    Some(serde_json::json!({ "type": "reload" }))
+                } else if changed.is_empty() {
+                    // e.g. an extracted stylesheet or an asset
+                    (previous.files != output.files)
+                        .then(|| serde_json::json!({ "type": "reload" }))
+                } else {
+                    updates += 1;
+                    let filename = format!("hot-update-{}.js", updates);
+                    files.insert(filename.clone(), hot_update(&changed).into_bytes());
+                    Some(serde_json::json!({ "type": "update", "file": filename }))
+                }
             }
+            _ => None,
+        };
+        *state.files.write().unwrap() = files;
+        if let Some(message) = message {
+            let message = message.to_string();
+            state
+                .hmr_clients
+                .lock()
+                .unwrap()
+                .retain_mut(|client| write_text_frame(client, &message).is_ok());
         }
-    });
-    deps
+        previous = Some(output);
+        Ok(())
+    })
 }
 
-fn resolve(path: &PathBuf, dep: &str) -> PathBuf {
-    use oxc_resolver::{ResolveOptions, Resolver};
-    let resolver = Resolver::new(ResolveOptions {
-        extensions: vec![".ts".to_string()],
-        ..Default::default()
-    });
-    let resolved = resolver.resolve(path.parent().unwrap(), dep).unwrap();
-    resolved.full_path()
+/// A script redefining the changed modules and applying them.
+fn hot_update(modules: &BTreeMap<&String, &String>) -> String {
+    let mut ret = vec!["globalThis.makoHotUpdate({".to_string()];
+    ret.extend(modules.iter().map(|(id, code)| {
+        format!(
+            "{}: function (module, exports, require) {{\n{}\n}},",
+            js_string(id),
+            code
+        )
+    }));
+    ret.push("});".to_string());
+    ret.join("\n")
 }
 
-/////////////////////////////////////////
-// Generate Stage
+/// Completes the websocket handshake, the connection is then only written to.
+fn accept_websocket(
+    mut stream: TcpStream,
+    request: &Request,
+    state: &DevServerState,
+) -> std::io::Result<()> {
+    let Some(key) = request.header("Sec-WebSocket-Key") else {
+        return write_response(&mut stream, "400 Bad Request", "text/plain", b"");
+    };
+    let accept = sha1_smol::Sha1::from(format!("{}258EAFA5-E914-47DA-95CA-C5AB0DC85B11", key))
+        .digest()
+        .bytes();
+    write!(
+        stream,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        base64::engine::general_purpose::STANDARD.encode(accept)
+    )?;
+    state.hmr_clients.lock().unwrap().push(stream);
+    Ok(())
+}
 
-fn generate(module_graph: &mut ModuleGraph, context: Arc<Context>) {
-    // TODO:
-    // - tree shaking
-    // - skip modules & module concatenation
-    // - chunk group and splitting
-    // - source map
-    // - minification
-    // - parallel
-    // - ...
+/// An unmasked, unfragmented websocket text frame.
+fn write_text_frame(stream: &mut TcpStream, text: &str) -> std::io::Result<()> {
+    let mut frame = vec![0x81];
+    match text.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend((len as u64).to_be_bytes());
+        }
+    }
+    frame.extend(text.as_bytes());
+    stream.write_all(&frame)
+}
 
-    let mut runtime = Runtime {
-        modules: HashMap::new(),
+fn handle_connection(
+    mut stream: TcpStream,
+    state: &DevServerState,
+    context: Arc<Context>,
+) -> std::io::Result<()> {
+    let request = read_request(&stream)?;
+    let path = request.target.split('?').next().unwrap_or("/");
+    if path == "/__mako_hmr"
+        && request
+            .header("Upgrade")
+            .map_or(false, |upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+    {
+        return accept_websocket(stream, &request, state);
+    }
+    if let Some((_, upstream)) = context
+        .config
+        .dev_server
+        .proxy
+        .iter()
+        .find(|(prefix, _)| path.starts_with(prefix.as_str()))
+    {
+        return match proxy(&request, upstream, &mut stream) {
+            Ok(()) => Ok(()),
+            Err(err) => write_response(
+                &mut stream,
+                "502 Bad Gateway",
+                "text/plain",
+                format!("proxy to {} failed: {}", upstream, err).as_bytes(),
+            ),
+        };
+    }
+    if request.method != "GET" && request.method != "HEAD" {
+        return write_response(&mut stream, "405 Method Not Allowed", "text/plain", b"");
+    }
+    let path = percent_decode(path);
+    let (status, content_type, body) = match static_file(&path, state, context.clone()) {
+        Some((content_type, body)) => ("200 OK", content_type, body),
+        // history api fallback for single page apps
+        None if path == "/"
+            || request
+                .header("Accept")
+                .map_or(false, |accept| accept.contains("text/html")) =>
+        {
+            ("200 OK", "text/html", index_html(state, context))
+        }
+        None => ("404 Not Found", "text/plain", b"Not Found".to_vec()),
     };
-    let module_paths = module_graph.modules.keys().cloned().collect::<Vec<_>>();
-    for path in module_paths {
-        let module = module_graph.modules.get_mut(&path).unwrap();
-        replace_deps(&mut module.ast, &module.deps);
-        tramsform_again(&mut module.ast, context.clone());
-        let code = ast_to_code(&module.ast, context.clone());
-        runtime.modules.insert(path.to_string(), code);
+    if request.method == "HEAD" {
+        // headers only, with the length of the body a GET would get
+        return write_head(&mut stream, status, content_type, body.len());
     }
-    let code = runtime.render(context.clone());
-    // write to disk
-    let output_dir = context.root.join("dist");
-    std::fs::create_dir_all(&output_dir).unwrap();
-    std::fs::write(output_dir.join("bundle.js"), code).unwrap();
+    write_response(&mut stream, status, content_type, &body)
 }
 
-struct Runtime {
-    modules: HashMap<String, String>,
+/// A build output under the public path, or a file in `public/`.
+fn static_file(
+    path: &str,
+    state: &DevServerState,
+    context: Arc<Context>,
+) -> Option<(&'static str, Vec<u8>)> {
+    let output = path
+        .strip_prefix(context.config.public_path.as_str())
+        .or_else(|| path.strip_prefix('/'))?;
+    if let Some(content) = state.files.read().unwrap().get(output) {
+        return Some((content_type(path), content.clone()));
+    }
+    let relative = Path::new(path.trim_start_matches('/'));
+    // keep requests inside `public/`
+    if relative
+        .components()
+        .any(|component| !matches!(component, std::path::Component::Normal(_)))
+    {
+        return None;
+    }
+    let file = context.root.join("public").join(relative);
+    let file = if file.is_dir() {
+        file.join("index.html")
+    } else {
+        file
+    };
+    let content = std::fs::read(&file).ok()?;
+    Some((content_type(&file.to_string_lossy()), content))
 }
 
-impl Runtime {
-    fn render(&self, context: Arc<Context>) -> String {
-        let mut ret = vec![];
-        ret.push(
-            r#"
-const modules = new Map();
-const define = (name, moduleFactory) => {
-  modules.set(name, moduleFactory);
-};
+/// `public/index.html`, or a page loading the entry chunk and its stylesheet.
+fn index_html(state: &DevServerState, context: Arc<Context>) -> Vec<u8> {
+    if let Ok(content) = std::fs::read(context.root.join("public/index.html")) {
+        return content;
+    }
+    let public_path = &context.config.public_path;
+    let script = context.config.output.filename.replace("[name]", "bundle");
+    let stylesheet = css_filename(&script);
+    let link = if state.files.read().unwrap().contains_key(&stylesheet) {
+        format!(
+            "\n  <link rel=\"stylesheet\" href=\"{}{}\">",
+            public_path, stylesheet
+        )
+    } else {
+        String::new()
+    };
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="utf-8">
+  <meta name="viewport" content="width=device-width, initial-scale=1">{}
+</head>
+<body>
+  <div id="root"></div>
+  <script src="{}{}"></script>
+</body>
+</html>
+"#,
+        link, public_path, script
+    )
+    .into_bytes()
+}
 
-const moduleCache = new Map();
-const requireModule = (name) => {
-  if (moduleCache.has(name)) {
-    return moduleCache.get(name).exports;
-  }
+fn content_type(path: &str) -> &'static str {
+    match extension(Path::new(path)) {
+        "html" => "text/html; charset=utf-8",
+        "js" | "mjs" | "cjs" => "text/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "avif" => "image/avif",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "wasm" => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
 
-  if (!modules.has(name)) {
-    throw new Error(`Module '${name}' does not exist.`);
-  }
+/// Larger request bodies are refused instead of buffered.
+const MAX_REQUEST_BODY: usize = 8 * 1024 * 1024;
 
-  const moduleFactory = modules.get(name);
-  const module = {
-    exports: {},
-  };
-  moduleCache.set(name, module);
-  moduleFactory(module, module.exports, requireModule);
-  return module.exports;
-};
-        "#
-            .to_string(),
-        );
-        self.modules.iter().for_each(|(path, code)| {
-            ret.push(format!(
-                "define('{}', function (module, exports, require) {{\n{}\n}});",
-                path, code
-            ));
-        });
-        ret.push(format!(
-            "requireModule('{}');",
-            context.root.join("index.ts").to_string_lossy()
+fn read_request(stream: &TcpStream) -> std::io::Result<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("invalid request line {:?}", line.trim()),
+        ));
+    };
+    let (method, target) = (method.to_string(), target.to_string());
+    let mut headers = vec![];
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+        let Some((key, value)) = line.trim_end().split_once(':') else {
+            break;
+        };
+        headers.push((key.trim().to_string(), value.trim().to_string()));
+    }
+    let mut request = Request {
+        method,
+        target,
+        headers,
+        body: vec![],
+    };
+    let length = request
+        .header("Content-Length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    if length > MAX_REQUEST_BODY {
+        write_response(
+            &mut stream.try_clone()?,
+            "413 Payload Too Large",
+            "text/plain",
+            b"",
+        )?;
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("request body of {} bytes is too large", length),
         ));
-        ret.join("\n")
     }
+    request.body = vec![0; length];
+    reader.read_exact(&mut request.body)?;
+    Ok(request)
 }
 
-fn tramsform_again(ast: &mut Ast, context: Arc<Context>) {
-    GLOBALS.set(&context.globals, || {
-        HELPERS.set(&Helpers::new(true), || {
-            ast.ast
-                .visit_mut_with(&mut inject_helpers(ast.unresolved_mark));
-            ast.ast.visit_mut_with(&mut common_js(
-                ast.unresolved_mark,
-                Default::default(),
-                FeatureFlag::empty(),
-                Some(&context.comments),
-            ));
-        });
-    });
+fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> std::io::Result<()> {
+    write_head(stream, status, content_type, body.len())?;
+    stream.write_all(body)
 }
 
-fn replace_deps(ast: &mut Ast, deps: &HashMap<String, PathBuf>) {
-    ast.ast.body.iter_mut().for_each(|item| {
-        if let ModuleItem::ModuleDecl(decl) = item {
-            if let ModuleDecl::Import(import) = decl {
-                let dep = import.src.value.to_string();
-                if let Some(path) = deps.get(&dep) {
-                    import.src = Box::new(path.to_string_lossy().to_string().into());
-                }
+fn write_head(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    content_length: usize,
+) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        status, content_type, content_length
+    )
+}
+
+/// Forwards the request to an `http://host[:port][/base]` upstream and
+/// streams its response back.
+fn proxy(request: &Request, upstream: &str, stream: &mut TcpStream) -> std::io::Result<()> {
+    let address = upstream.strip_prefix("http://").ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "only http:// upstreams are supported",
+        )
+    })?;
+    let (host, base) = match address.find('/') {
+        Some(index) => address.split_at(index),
+        None => (address, ""),
+    };
+    let mut upstream_stream = if host.contains(':') {
+        TcpStream::connect(host)?
+    } else {
+        TcpStream::connect((host, 80))?
+    };
+    write!(
+        upstream_stream,
+        "{} {}{} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+        request.method,
+        base.trim_end_matches('/'),
+        request.target,
+        host
+    )?;
+    request
+        .headers
+        .iter()
+        .filter(|(key, _)| {
+            !key.eq_ignore_ascii_case("host") && !key.eq_ignore_ascii_case("connection")
+        })
+        .try_for_each(|(key, value)| write!(upstream_stream, "{}: {}\r\n", key, value))?;
+    upstream_stream.write_all(b"\r\n")?;
+    upstream_stream.write_all(&request.body)?;
+    std::io::copy(&mut upstream_stream, stream)?;
+    Ok(())
+}
+
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let hex = bytes
+            .get(index + 1..index + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[index], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                index += 1;
             }
         }
-    });
+    }
+    String::from_utf8_lossy(&decoded).to_string()
 }
 
 /////////////////////////////////////////
 // Utils
 
+use base64::Engine;
+use rayon::prelude::*;
+use sourcemap::SourceMapBuilder;
 use swc_core::{
     common::{
-        chain, input::StringInput, sync::Lrc, util::take::Take, FileName, Globals, Mark, SourceMap,
+        chain,
+        collections::AHashSet,
+        comments::{CommentKind, Comments},
+        input::StringInput,
+        pass::Optional,
+        source_map::SourceMapGenConfig,
+        sync::Lrc,
+        util::take::Take,
+        BytePos, FileName, Globals, LineCol, Mark, SourceMap, Span, SyntaxContext, DUMMY_SP,
         GLOBALS,
     },
+    css::{self, visit::VisitMutWith as _},
     ecma::{
         ast::{Module as SwcModule, *},
+        atoms::JsWord,
         codegen::{self, text_writer::JsWriter, Emitter},
-        parser::{lexer::Lexer, Parser, Syntax},
+        minifier::{
+            optimize,
+            option::{CompressOptions, ExtraOptions, MangleOptions, MinifyOptions},
+        },
+        parser::{error::Error as ParserError, lexer::Lexer, EsConfig, Parser, Syntax, TsConfig},
         preset_env,
         transforms::{
             base::{
                 feature::FeatureFlag,
+                fixer::fixer,
                 helpers::inject_helpers,
                 helpers::{Helpers, HELPERS},
+                hygiene::hygiene,
                 resolver,
             },
             module::common_js,
+            react, typescript,
         },
-        visit::{Fold, VisitMutWith},
+        utils::{collect_decls, find_pat_ids, private_ident},
+        visit::{Fold, FoldWith, Visit, VisitMut, VisitMutWith, VisitWith},
     },
 };
+use swc_error_reporters::handler::try_with_handler;
 use swc_node_comments::SwcComments;
 
+/// `path` relative to `base` with `/` separators, going up with `..` if needed.
+fn relative_path(base: &Path, path: &Path) -> String {
+    let base = base.components().collect::<Vec<_>>();
+    let path = path.components().collect::<Vec<_>>();
+    let common = base
+        .iter()
+        .zip(&path)
+        .take_while(|(base, path)| base == path)
+        .count();
+    std::iter::repeat("..".to_string())
+        .take(base.len() - common)
+        .chain(
+            path[common..]
+                .iter()
+                .map(|component| component.as_os_str().to_string_lossy().to_string()),
+        )
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// `value` as a JavaScript string literal, `{:?}` writes Rust escapes like `\u{200b}`.
+fn js_string(value: &str) -> String {
+    serde_json::Value::from(value).to_string()
+}
+
+fn extension(path: &Path) -> &str {
+    split_query(path)
+        .0
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("")
+}
+
+/// The file of a resolved path and its query without `?`, the resolver keeps
+/// both, e.g. `/src/logo.svg?raw`. A `#fragment` is dropped.
+fn split_query(path: &Path) -> (&Path, Option<&str>) {
+    let Some(full) = path.to_str() else {
+        return (path, None);
+    };
+    let name = full.rfind(std::path::is_separator).map_or(0, |i| i + 1);
+    let Some(end) = full[name..].find(['?', '#']).map(|i| name + i) else {
+        return (path, None);
+    };
+    let query = full[end..]
+        .strip_prefix('?')
+        .map(|query| query.split('#').next().unwrap_or(query));
+    (Path::new(&full[..end]), query)
+}
+
+/// `?raw`, `?url` and `?inline` load any file as an asset.
+fn asset_query(path: &Path) -> Option<&str> {
+    split_query(path)
+        .1
+        .filter(|query| matches!(*query, "raw" | "url" | "inline"))
+}
+
+fn is_node_module(path: &Path) -> bool {
+    path.components()
+        .any(|component| component.as_os_str() == "node_modules")
+}
+
+/// Modules loaded as JS, after conversion for JSON.
+fn is_script(path: &Path) -> bool {
+    matches!(
+        extension(path),
+        "js" | "jsx" | "mjs" | "cjs" | "ts" | "tsx" | "mts" | "cts" | "json"
+    )
+}
+
+fn is_typescript(path: &Path) -> bool {
+    matches!(extension(path), "ts" | "tsx" | "mts" | "cts")
+}
+
+fn syntax(path: &Path) -> Syntax {
+    match extension(path) {
+        "ts" | "mts" | "cts" => Syntax::Typescript(TsConfig::default()),
+        "tsx" => Syntax::Typescript(TsConfig {
+            tsx: true,
+            ..Default::default()
+        }),
+        "jsx" => Syntax::Es(EsConfig {
+            jsx: true,
+            ..Default::default()
+        }),
+        _ => Syntax::Es(Default::default()),
+    }
+}
+
+fn is_jsx(path: &Path) -> bool {
+    matches!(extension(path), "jsx" | "tsx")
+}
+
+/// Parses a module, failing with every syntax error the parser found.
 fn code_to_ast(
     code: String,
-    path: &PathBuf,
+    path: &Path,
+    syntax: Syntax,
     cm: Lrc<SourceMap>,
     comments: &SwcComments,
-) -> SwcModule {
-    let path = path.to_string_lossy().to_string();
-    let file = cm.new_source_file(FileName::Custom(path.into()), code);
+) -> Result<SwcModule, Vec<ParserError>> {
+    let file = cm.new_source_file(FileName::Custom(path.to_string_lossy().to_string()), code);
     let lexer = Lexer::new(
-        Syntax::Es(Default::default()),
+        syntax,
         EsVersion::latest(),
         StringInput::from(&*file),
         Some(&comments),
     );
     let mut parser = Parser::new_from(lexer);
-    let module = parser.parse_module().unwrap();
-    module
+    let module = parser.parse_module();
+    let mut errors = parser.take_errors();
+    match module {
+        Ok(module) if errors.is_empty() => Ok(module),
+        Ok(_) => Err(errors),
+        Err(error) => {
+            errors.push(error);
+            Err(errors)
+        }
+    }
+}
+
+fn code_to_expr(code: String, path: &Path, cm: Lrc<SourceMap>) -> Result<Expr, Vec<ParserError>> {
+    let file = cm.new_source_file(FileName::Custom(path.to_string_lossy().to_string()), code);
+    let lexer = Lexer::new(
+        Syntax::Es(Default::default()),
+        EsVersion::latest(),
+        StringInput::from(&*file),
+        None,
+    );
+    let mut parser = Parser::new_from(lexer);
+    parser
+        .parse_expr()
+        .map(|expr| *expr)
+        .map_err(|error| vec![error])
 }
 
-fn ast_to_code(ast: &Ast, context: Arc<Context>) -> String {
+fn ast_to_code(
+    ast: &Ast,
+    context: Arc<Context>,
+) -> std::io::Result<(String, Vec<(BytePos, LineCol)>)> {
     let mut buf = vec![];
     let mut source_map_buf = Vec::new();
     {
@@ -319,18 +5834,392 @@ fn ast_to_code(ast: &Ast, context: Arc<Context>) -> String {
                 Some(&mut source_map_buf),
             )),
         };
-        emitter.emit_module(&ast.ast).unwrap();
+        emitter.emit_module(&ast.ast)?;
     }
-    let code = String::from_utf8(buf).unwrap();
-    code
+    // the emitter only writes valid utf-8
+    Ok((String::from_utf8(buf).unwrap(), source_map_buf))
+}
+
+/// Names sources by file path and always embeds their content.
+struct SourceMapConfig;
+
+impl SourceMapGenConfig for SourceMapConfig {
+    fn file_name_to_source(&self, f: &FileName) -> String {
+        match f {
+            FileName::Custom(path) => path.clone(),
+            f => f.to_string(),
+        }
+    }
+
+    fn inline_sources_content(&self, _f: &FileName) -> bool {
+        true
+    }
+}
+
+/// Copies the tokens of `map` into `builder`, shifted down by `line_offset`.
+fn add_source_map(builder: &mut SourceMapBuilder, map: &sourcemap::SourceMap, line_offset: u32) {
+    map.tokens().for_each(|token| {
+        let raw = builder.add(
+            token.get_dst_line() + line_offset,
+            token.get_dst_col(),
+            token.get_src_line(),
+            token.get_src_col(),
+            token.get_source(),
+            token.get_name(),
+        );
+        if raw.src_id != !0 && !builder.has_source_contents(raw.src_id) {
+            let contents = map.get_source_contents(token.get_src_id());
+            builder.set_source_contents(raw.src_id, contents);
+        }
+    });
+}
+
+/// Maps the tokens of `map` through the input source maps of their sources,
+/// looked up by source name. Tokens of other sources are kept as they are.
+fn chain_source_maps(
+    map: &sourcemap::SourceMap,
+    inputs: &HashMap<String, sourcemap::SourceMap>,
+) -> sourcemap::SourceMap {
+    let mut builder = SourceMapBuilder::new(None);
+    map.tokens().for_each(|token| {
+        let input = token.get_source().and_then(|source| inputs.get(source));
+        let (original, original_map) = match input {
+            Some(input) => match input.lookup_token(token.get_src_line(), token.get_src_col()) {
+                Some(original) => (original, input),
+                None => return,
+            },
+            None => (token, map),
+        };
+        let raw = builder.add(
+            token.get_dst_line(),
+            token.get_dst_col(),
+            original.get_src_line(),
+            original.get_src_col(),
+            original.get_source(),
+            original.get_name().or(token.get_name()),
+        );
+        if raw.src_id != !0 && !builder.has_source_contents(raw.src_id) {
+            let contents = original_map.get_source_contents(original.get_src_id());
+            builder.set_source_contents(raw.src_id, contents);
+        }
+    });
+    builder.into_sourcemap()
+}
+
+/////////////////////////////////////////
+// CLI
+
+use clap::{Parser as _, ValueEnum};
+
+/// A toy bundler for JavaScript and TypeScript.
+#[derive(clap::Parser)]
+#[command(version)]
+struct Cli {
+    /// Project root, defaults to the current directory
+    root: Option<PathBuf>,
+    /// Entry files, relative to the root
+    #[arg(short, long = "entry")]
+    entries: Vec<PathBuf>,
+    /// Output directory, relative to the root
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Build mode
+    #[arg(short, long, value_enum)]
+    mode: Option<Mode>,
+    /// Rebuild when files change
+    #[arg(short, long)]
+    watch: bool,
+    /// Serve the output on localhost, rebuilding on changes
+    #[arg(short, long)]
+    serve: bool,
+    /// Print debug information, like what tree shaking removed
+    #[arg(long)]
+    debug: bool,
+    /// Config file, defaults to `mako.config.json` in the root
+    #[arg(short, long)]
+    config: Option<PathBuf>,
 }
 
 /////////////////////////////////////////
 // Main
 
-fn main() {
-    let root = std::env::current_dir().unwrap().join("examples/normal");
-    compile(CompileParams { root });
-    println!("Done!");
-    println!("Run `node examples/normal/dist/bundle.js` to see the result.");
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let cwd = std::env::current_dir().unwrap();
+    let root = cwd.join(cli.root.unwrap_or_default());
+    let root = match root.canonicalize() {
+        Ok(canonical) if canonical.is_dir() => canonical,
+        _ => {
+            eprintln!("error: root {} is not a directory", root.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    let config = cli.config.map(|config| cwd.join(config));
+    let mut config = match Config::load(&root, config.as_deref()) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    // command-line flags take precedence over the config file
+    if !cli.entries.is_empty() {
+        config.entry = cli.entries;
+    }
+    if let Some(output) = cli.output {
+        config.output.path = output;
+    }
+    if let Some(mode) = cli.mode {
+        config.mode = mode;
+    }
+    config.debug |= cli.debug;
+    if let Some(entry) = config
+        .entry
+        .iter()
+        .find(|entry| !root.join(entry).is_file())
+    {
+        eprintln!("error: entry {} does not exist", root.join(entry).display());
+        return ExitCode::FAILURE;
+    }
+    if let Some(tsconfig) = &config.resolve.tsconfig {
+        if !root.join(tsconfig).is_file() {
+            eprintln!(
+                "error: tsconfig {} does not exist",
+                root.join(tsconfig).display()
+            );
+            return ExitCode::FAILURE;
+        }
+    }
+    let params = CompileParams {
+        root,
+        hmr: cli.serve && config.dev_server.hmr,
+        config,
+    };
+    if cli.watch || cli.serve {
+        let context = match create_context(params) {
+            Ok(context) => context,
+            Err(err) => {
+                eprintln!("error: {}", err);
+                return ExitCode::FAILURE;
+            }
+        };
+        if cli.serve {
+            // only returns when the server can't start
+            if let Err(err) = serve(context) {
+                eprintln!("error: {}", err);
+            }
+            return ExitCode::FAILURE;
+        }
+        watch(context.clone(), |output| {
+            write_output(&output.files, context.clone())
+        });
+    }
+    match compile(params) {
+        Ok(()) => {
+            println!("Done!");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn external(externals: serde_json::Value, specifier: &str) -> Option<ExternalModule> {
+        deserialize_externals(externals)
+            .unwrap()
+            .iter()
+            .find_map(|external| external.matches(specifier))
+    }
+
+    #[test]
+    fn external_types() {
+        let parse = |value| {
+            let external = External::parse("x", value).unwrap();
+            (external.kind, external.name)
+        };
+        assert_eq!(parse("X"), (ExternalKind::Global, "X".to_string()));
+        assert_eq!(parse("global X"), (ExternalKind::Global, "X".to_string()));
+        assert_eq!(
+            parse("commonjs x"),
+            (ExternalKind::CommonJs, "x".to_string())
+        );
+        assert_eq!(parse("module x"), (ExternalKind::Module, "x".to_string()));
+        assert!(External::parse("x", "amd x").is_err());
+        assert!(External::parse("/(/", "x").is_err());
+    }
+
+    #[test]
+    fn external_patterns() {
+        let externals = serde_json::json!({
+            "react": "React",
+            "lodash/*": "commonjs [request]",
+            "/^@scope\\//": "module https://cdn/[request]",
+        });
+        assert_eq!(
+            external(externals.clone(), "react"),
+            Some(ExternalModule {
+                kind: ExternalKind::Global,
+                name: "React".to_string()
+            })
+        );
+        assert_eq!(external(externals.clone(), "react-dom"), None);
+        assert_eq!(
+            external(externals.clone(), "lodash/get"),
+            Some(ExternalModule {
+                kind: ExternalKind::CommonJs,
+                name: "lodash/get".to_string()
+            })
+        );
+        assert_eq!(external(externals.clone(), "lodash"), None);
+        assert_eq!(
+            external(externals, "@scope/ui"),
+            Some(ExternalModule {
+                kind: ExternalKind::Module,
+                name: "https://cdn/@scope/ui".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn external_order() {
+        // exact names first, then patterns in sorted order
+        let externals = serde_json::json!({
+            "re*": "prefix",
+            "/^rea/": "regex",
+            "react": "exact",
+        });
+        let name = |specifier| external(externals.clone(), specifier).map(|external| external.name);
+        assert_eq!(name("react"), Some("exact".to_string()));
+        assert_eq!(name("react-dom"), Some("regex".to_string()));
+        assert_eq!(name("redux"), Some("prefix".to_string()));
+    }
+
+    #[test]
+    fn hash_ids_are_stable() {
+        let paths = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| (format!("/root/{}", name), format!("./{}", name)))
+                .collect::<HashMap<_, _>>()
+        };
+        let before = hash_ids(paths(&["index.ts", "a.ts", "b.ts"]));
+        let after = hash_ids(paths(&["index.ts", "a.ts", "b.ts", "c.ts"]));
+        assert_eq!(after.len(), 4);
+        before.iter().for_each(|(path, id)| {
+            assert_eq!(id.len(), 8);
+            assert_eq!(&after[path], id);
+        });
+    }
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_match("**/*.css", "a.css"));
+        assert!(glob_match("**/*.css", "src/styles/a.css"));
+        assert!(glob_match("src/**/index.ts", "src/index.ts"));
+        assert!(glob_match("src/**/index.ts", "src/a/b/index.ts"));
+        assert!(glob_match("src/*.ts", "src/a.ts"));
+        assert!(!glob_match("src/*.ts", "src/a/b.ts"));
+        assert!(glob_match("a?.ts", "ab.ts"));
+        assert!(!glob_match("a?.ts", "a.ts"));
+        assert!(!glob_match("a?b.ts", "a/b.ts"));
+        assert!(!glob_match("*.css", "a.ts"));
+    }
+
+    #[test]
+    fn side_effects_patterns() {
+        assert!(side_effects_match("*.css", "a.css"));
+        assert!(side_effects_match("*.css", "src/styles/a.css"));
+        assert!(side_effects_match("polyfill.js", "lib/polyfill.js"));
+        assert!(side_effects_match("./src/effect.js", "src/effect.js"));
+        assert!(!side_effects_match("./src/effect.js", "lib/src/effect.js"));
+        assert!(!side_effects_match("src/*.js", "src/a/b.js"));
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("/a%20b.js"), "/a b.js");
+        assert_eq!(percent_decode("/%E4%BD%A0.js"), "/你.js");
+        assert_eq!(percent_decode("/100%"), "/100%");
+        assert_eq!(percent_decode("/%zz%2"), "/%zz%2");
+    }
+
+    #[test]
+    fn queries() {
+        let split = |path| {
+            let (file, query) = split_query(Path::new(path));
+            (file.to_str().unwrap(), query)
+        };
+        assert_eq!(split("/src/a.ts"), ("/src/a.ts", None));
+        assert_eq!(split("/src/logo.svg?raw"), ("/src/logo.svg", Some("raw")));
+        assert_eq!(split("/src/logo.svg?url#x"), ("/src/logo.svg", Some("url")));
+        assert_eq!(split("/src/font.svg#icon"), ("/src/font.svg", None));
+        // only the file name can have a query
+        assert_eq!(split("/a?b/c.ts"), ("/a?b/c.ts", None));
+    }
+
+    #[test]
+    fn relative_paths() {
+        let relative = |base, path| relative_path(Path::new(base), Path::new(path));
+        assert_eq!(relative("/app", "/app/src/a.ts"), "src/a.ts");
+        assert_eq!(relative("/app/src", "/app/lib/b.ts"), "../lib/b.ts");
+        assert_eq!(relative("/app", "/lib/c.ts"), "../lib/c.ts");
+        assert_eq!(relative("/app", "/app"), "");
+    }
+
+    #[test]
+    fn css_specifiers() {
+        assert_eq!(css_specifier("a.png").as_deref(), Some("./a.png"));
+        assert_eq!(css_specifier("./a.png").as_deref(), Some("./a.png"));
+        assert_eq!(css_specifier("../a.png").as_deref(), Some("../a.png"));
+        assert_eq!(css_specifier("~pkg/a.png").as_deref(), Some("pkg/a.png"));
+        assert_eq!(
+            css_specifier("font.eot?#iefix").as_deref(),
+            Some("./font.eot")
+        );
+        for url in [
+            "",
+            "/a.png",
+            "#filter",
+            "data:image/png;base64,",
+            "https://a/b.png",
+        ] {
+            assert_eq!(css_specifier(url), None, "{}", url);
+        }
+    }
+
+    #[test]
+    fn json_modules() {
+        let context = create_context(CompileParams {
+            root: PathBuf::from("/app"),
+            config: Config {
+                cache: false,
+                ..Default::default()
+            },
+            hmr: false,
+        })
+        .unwrap();
+        let path = Path::new("/app/data.json");
+        let module = |json: &str| json_to_module(json, path, context.clone());
+        assert_eq!(
+            module(r#"{"a": 1, "not-ident": [2]}"#).unwrap(),
+            "export const a = 1;\nexport default { \"a\": a, \"not-ident\": [2] };"
+        );
+        assert_eq!(module("[1, 2]").unwrap(), "export default [1,2];");
+        assert!(matches!(module("{"), Err(BuildError::Parse { .. })));
+        // large files are parsed at runtime
+        let large = format!(
+            r#"{{"JSON": 1, "b": "{}"}}"#,
+            "x".repeat(JSON_PARSE_THRESHOLD)
+        );
+        let code = module(&large).unwrap();
+        assert!(code.starts_with("const __json = JSON.parse("), "{}", code);
+        assert!(code.contains("export const b = __json[\"b\"];"), "{}", code);
+        assert!(!code.contains("export const JSON"), "{}", code);
+    }
 }
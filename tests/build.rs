@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Builds `tests/fixtures/<fixture>` into a fresh output directory, returning
+/// that directory.
+fn build(fixture: &str, output: &str, args: &[&str]) -> PathBuf {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(fixture);
+    let output = Path::new(env!("CARGO_TARGET_TMPDIR")).join(output);
+    let _ = std::fs::remove_dir_all(&output);
+    let status = Command::new(env!("CARGO_BIN_EXE_toy-mako"))
+        .arg(&root)
+        .arg("--output")
+        .arg(&output)
+        .args(args)
+        // several workers even on a single core
+        .env("RAYON_NUM_THREADS", "8")
+        .status()
+        .unwrap();
+    assert!(status.success(), "building {} failed", fixture);
+    output
+}
+
+/// Runs `script` with Node, returning what it printed.
+fn run_node(script: &Path) -> String {
+    let output = Command::new("node").arg(script).output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Copies `tests/fixtures/<fixture>` into a fresh project with a
+/// `node_modules` directory, so the cache is written outside the fixture.
+fn copy_fixture(fixture: &str, name: &str) -> PathBuf {
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(fixture);
+    let root = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(root.join("node_modules")).unwrap();
+    for entry in std::fs::read_dir(fixture).unwrap() {
+        let entry = entry.unwrap();
+        std::fs::copy(entry.path(), root.join(entry.file_name())).unwrap();
+    }
+    root
+}
+
+fn read_output(output: &Path) -> BTreeMap<String, Vec<u8>> {
+    std::fs::read_dir(output)
+        .unwrap()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            let name = entry.file_name().to_string_lossy().to_string();
+            (name, std::fs::read(entry.path()).unwrap())
+        })
+        .collect()
+}
+
+#[test]
+fn tree_shaking_keeps_export_star_modules_hoisted() {
+    // `b.ts` is removed, `lib.ts` re-exports from it
+    let output = build("hoist-export-star", "hoist-export-star", &[]);
+    let bundle = std::fs::read_to_string(output.join("bundle.js")).unwrap();
+    assert!(!bundle.contains("define("), "{}", bundle);
+    assert!(bundle.contains("var a = 1;"), "{}", bundle);
+}
+
+#[test]
+fn hoisted_default_exports_do_not_collide_with_module_bindings() {
+    // both modules declare their own `_default`
+    let output = build("hoist-default-name", "hoist-default-name", &[]);
+    let bundle = std::fs::read_to_string(output.join("bundle.js")).unwrap();
+    assert!(!bundle.contains("define("), "{}", bundle);
+    assert!(!bundle.contains("const _default"), "{}", bundle);
+    assert_eq!(
+        run_node(&output.join("bundle.js")),
+        "def mine fn also mine\n"
+    );
+}
+
+#[test]
+fn parallel_builds_are_deterministic() {
+    for module_ids in ["path", "numeric", "hash"] {
+        let config = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{}.json", module_ids));
+        let json = format!(r#"{{"moduleIds": "{}", "cache": false}}"#, module_ids);
+        std::fs::write(&config, json).unwrap();
+        let config = config.to_string_lossy();
+        let outputs = ["first", "second"].map(|run| {
+            let output = format!("chunks-{}-{}", module_ids, run);
+            read_output(&build("chunks", &output, &["--config", &config]))
+        });
+        assert!(outputs[0].len() > 1, "no chunks emitted");
+        assert_eq!(
+            outputs[0].keys().collect::<Vec<_>>(),
+            outputs[1].keys().collect::<Vec<_>>(),
+            "module ids: {}",
+            module_ids
+        );
+        for (name, content) in &outputs[0] {
+            assert!(
+                *content == outputs[1][name],
+                "{} differs between builds with {} module ids",
+                name,
+                module_ids
+            );
+        }
+    }
+}
+
+#[test]
+fn url_and_worker_dependencies_point_at_emitted_files() {
+    let output = build("url-worker", "url-worker", &[]);
+    let stdout = run_node(&output.join("bundle.js"));
+    let url = |kind: &str| {
+        let prefix = format!("{} file://", kind);
+        let line = stdout.lines().find(|line| line.starts_with(&prefix));
+        let path = PathBuf::from(&line.unwrap_or_else(|| panic!("{}", stdout))[prefix.len()..]);
+        assert!(path.is_file(), "{} does not exist", path.display());
+        path
+    };
+    assert!(url("asset").starts_with(&output));
+    let worker = url("worker");
+    assert_eq!(run_node(&worker), "worker ran\n");
+    // the worker's modules are only in its own chunk
+    let bundle = std::fs::read_to_string(output.join("bundle.js")).unwrap();
+    assert!(!bundle.contains("worker ran"), "{}", bundle);
+}
+
+#[test]
+fn module_externals_are_minified_as_a_module() {
+    let output = build("module-externals", "module-externals", &[]);
+    let bundle = std::fs::read_to_string(output.join("bundle.js")).unwrap();
+    assert!(bundle.starts_with("import"), "{}", bundle);
+    // the entry chunk is loaded as a module
+    let module = output.join("bundle.mjs");
+    std::fs::write(&module, bundle).unwrap();
+    assert_eq!(run_node(&module), "b.txt\n");
+}
+
+#[test]
+fn cached_modules_report_resolve_errors_in_the_loaded_file() {
+    let root = copy_fixture("missing-import", "missing-import");
+    for run in ["cold", "warm"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_toy-mako"))
+            .arg(&root)
+            .arg("--output")
+            .arg(root.join("dist"))
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("index.ts:7:8"), "{} build: {}", run, stderr);
+    }
+}
+
+#[test]
+fn cache_keeps_fingerprints_of_other_builds() {
+    let root = copy_fixture("chunks", "cache-fingerprints");
+    let cache = root.join("node_modules/.cache/mako");
+    let fingerprints = || std::fs::read_dir(&cache).unwrap().count();
+    let mut builds = 0;
+    let mut build = |config: &str| {
+        builds += 1;
+        let config_file = root.join(format!("{}.json", builds));
+        std::fs::write(&config_file, config).unwrap();
+        let status = Command::new(env!("CARGO_BIN_EXE_toy-mako"))
+            .arg(&root)
+            .arg("--output")
+            .arg(root.join("dist"))
+            .arg("--config")
+            .arg(&config_file)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    };
+    build(r#"{"mode": "development"}"#);
+    build(r#"{"mode": "production"}"#);
+    assert_eq!(fingerprints(), 2);
+    for i in 0..5 {
+        build(&format!(r#"{{"define": {{"N": "{}"}}}}"#, i));
+    }
+    assert_eq!(fingerprints(), 4);
+}
+
+#[test]
+fn side_effects_field_drops_unused_modules() {
+    // `pure` has `"sideEffects": false`, `listed` lists its modules with effects
+    let output = build("side-effects", "side-effects", &[]);
+    let bundle = std::fs::read_to_string(output.join("bundle.js")).unwrap();
+    assert!(!bundle.contains("pure setup"), "{}", bundle);
+    assert!(!bundle.contains("unlisted"), "{}", bundle);
+    assert_eq!(
+        run_node(&output.join("bundle.js")),
+        "listed effect\npolyfill a\nused\n"
+    );
+}
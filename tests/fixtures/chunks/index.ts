@@ -0,0 +1,6 @@
+import { a } from './a';
+import { b } from './b';
+
+console.log(a, b);
+import('./page1').then(({ page1 }) => console.log(page1));
+import('./page2').then(({ page2 }) => console.log(page2));
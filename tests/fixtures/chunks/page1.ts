@@ -0,0 +1,4 @@
+import { c } from './c';
+import { shared } from './shared';
+
+export const page1 = `${shared} ${c}`;
@@ -0,0 +1,3 @@
+import { shared } from './shared';
+
+export const page2 = shared.toUpperCase();
@@ -0,0 +1,5 @@
+const _default = 'also mine';
+export const v = _default;
+export default function () {
+  return 'fn';
+}
@@ -0,0 +1,4 @@
+import def, { w } from './m';
+import fn, { v } from './f';
+
+console.log(def, w, fn(), v);
@@ -0,0 +1,3 @@
+const _default = 'mine';
+export const w = _default;
+export default 'def';
@@ -0,0 +1,3 @@
+import { basename } from 'path-module';
+
+console.log(basename('/a/b.txt'));
@@ -0,0 +1,7 @@
+import { used } from './pure';
+import './pure/setup';
+import './listed/effect';
+import './listed/lib/polyfill-a';
+import './listed/other';
+
+console.log(used);
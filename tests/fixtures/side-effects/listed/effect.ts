@@ -0,0 +1 @@
+console.log("listed effect");
@@ -0,0 +1,5 @@
+import './setup';
+
+const logo = new URL('./logo.png', import.meta.url);
+console.log('asset', logo.href);
+new Worker(new URL('./worker.ts', import.meta.url));
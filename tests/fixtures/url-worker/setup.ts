@@ -0,0 +1,6 @@
+// Node has no global `Worker`, record the URL instead
+(globalThis as any).Worker = class {
+  constructor(url: URL) {
+    console.log('worker', url.href);
+  }
+};
@@ -0,0 +1,3 @@
+import { message } from './message';
+
+console.log(message);